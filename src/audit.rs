@@ -0,0 +1,96 @@
+use std::fmt::Write;
+
+use fnv::FnvHashMap;
+
+/// Why an insert was rejected: the ids it matched and their similarities,
+/// best first.
+pub(crate) struct AuditRecord {
+    pub(crate) id: String,
+    pub(crate) timestamp: f64,
+    pub(crate) matches: Vec<(String, f64)>,
+}
+
+/// Rejected inserts, kept while audit mode is enabled.
+#[derive(Default)]
+pub(crate) struct AuditLog {
+    records: Vec<AuditRecord>,
+    /// Positions in `records` of each rejected id, oldest first.
+    by_id: FnvHashMap<String, Vec<usize>>,
+}
+
+impl AuditLog {
+    pub(crate) fn push(&mut self, id: &str, timestamp: f64, mut matches: Vec<(String, f64)>) {
+        matches.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        self.by_id
+            .entry(id.to_string())
+            .or_default()
+            .push(self.records.len());
+        self.records.push(AuditRecord {
+            id: id.to_string(),
+            timestamp,
+            matches,
+        });
+    }
+
+    pub(crate) fn get(&self, id: &str) -> impl Iterator<Item = &AuditRecord> {
+        self.by_id
+            .get(id)
+            .into_iter()
+            .flatten()
+            .map(|&i| &self.records[i])
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// One JSON object per line, oldest first:
+    ///
+    /// ```text
+    /// {"id": "b", "timestamp": 1700000000.0, "matches": [{"id": "a", "similarity": 0.9}]}
+    /// ```
+    pub(crate) fn to_jsonl(&self) -> String {
+        let mut out = String::new();
+        for record in &self.records {
+            out.push_str("{\"id\": ");
+            write_json_str(&mut out, &record.id);
+            write!(
+                out,
+                ", \"timestamp\": {:?}, \"matches\": [",
+                record.timestamp
+            )
+            .unwrap();
+            for (n, (id, similarity)) in record.matches.iter().enumerate() {
+                if n > 0 {
+                    out.push_str(", ");
+                }
+                out.push_str("{\"id\": ");
+                write_json_str(&mut out, id);
+                write!(out, ", \"similarity\": {:?}}}", similarity).unwrap();
+            }
+            out.push_str("]}\n");
+        }
+        out
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.records.clear();
+        self.by_id.clear();
+    }
+}
+
+fn write_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
@@ -0,0 +1,109 @@
+use crate::hll::mix;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+/// b-bit minwise hashing: every slot keeps only the low `bits` bits of a
+/// mixed hash of its value, packed into 64-bit words. Two unequal slots
+/// still agree with probability 2^-bits, which `similarity` corrects for.
+#[derive(Clone)]
+pub(crate) struct PackedSignatures {
+    bits: u32,
+    codes: Vec<Vec<u64>>,
+}
+
+/// The `bits`-bit code of a slot value with bit pattern `value`.
+pub(crate) fn slot_code(value: u32, bits: u32) -> u64 {
+    mix(value as u64) & ((1u64 << bits) - 1)
+}
+
+impl PackedSignatures {
+    pub(crate) fn new(bits: u32) -> PyResult<Self> {
+        if ![1, 2, 4, 8].contains(&bits) {
+            return Err(PyValueError::new_err("bits must be one of 1, 2, 4 or 8"));
+        }
+        Ok(PackedSignatures {
+            bits,
+            codes: Vec::new(),
+        })
+    }
+
+    pub(crate) fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    fn lanes(&self) -> usize {
+        (64 / self.bits) as usize
+    }
+
+    pub(crate) fn pack(&self, data_bytes: &[Vec<u8>]) -> Vec<u64> {
+        data_bytes
+            .chunks(self.lanes())
+            .map(|chunk| {
+                chunk.iter().enumerate().fold(0u64, |word, (k, bytes)| {
+                    let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                    word | slot_code(value, self.bits) << (k as u32 * self.bits)
+                })
+            })
+            .collect()
+    }
+
+    /// The packed codes of every entry, in entry order.
+    pub(crate) fn codes(&self) -> &[Vec<u64>] {
+        &self.codes
+    }
+
+    pub(crate) fn push(&mut self, codes: Vec<u64>) {
+        self.codes.push(codes);
+    }
+
+    /// Drops the codes of every entry `i` with `removed[i]`.
+    pub(crate) fn retain(&mut self, removed: &[bool]) {
+        let mut removed = removed.iter();
+        self.codes.retain(|_| !removed.next().unwrap());
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.codes.len()
+    }
+
+    /// Whether every entry has the words of a `width`-slot signature.
+    pub(crate) fn fits(&self, width: usize) -> bool {
+        let words = width.div_ceil(self.lanes());
+        self.codes.iter().all(|codes| codes.len() == words)
+    }
+
+    /// Bytes `shrink_to_fit` would free.
+    pub(crate) fn slack(&self) -> usize {
+        (self.codes.capacity() - self.codes.len()) * std::mem::size_of::<Vec<u64>>()
+    }
+
+    pub(crate) fn shrink_to_fit(&mut self) {
+        self.codes.shrink_to_fit();
+    }
+
+    /// Jaccard estimate between the packed `query` of `len` slots and the
+    /// first `len` slots of entry `i`.
+    pub(crate) fn similarity(&self, query: &[u64], len: usize, i: usize) -> f64 {
+        let lanes = self.lanes();
+        let low_bits = u64::MAX / ((1u64 << self.bits) - 1);
+        let mut mismatches = 0;
+        for (n, (&a, &b)) in query.iter().zip(&self.codes[i]).enumerate() {
+            let mut x = a ^ b;
+            let used = (len - n * lanes).min(lanes);
+            if used < lanes {
+                x &= (1u64 << (used as u32 * self.bits)) - 1;
+            }
+            // Fold every lane onto its lowest bit so each differing lane
+            // counts once.
+            let mut shift = 1;
+            while shift < self.bits {
+                x |= x >> shift;
+                shift *= 2;
+            }
+            mismatches += (x & low_bits).count_ones() as usize;
+        }
+        let agreement = 1.0 - mismatches as f64 / len as f64;
+        let collision = 0.5f64.powi(self.bits as i32);
+        ((agreement - collision) / (1.0 - collision)).max(0.0)
+    }
+}
@@ -0,0 +1,347 @@
+use crate::minhash::{SuperMinHasher, SuperMinHasherLSH, LSH};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::collections::HashSet;
+
+/// The options set on a builder, passed on as constructor keyword arguments.
+struct Options(Py<PyDict>);
+
+impl Options {
+    fn new(py: Python<'_>) -> Self {
+        Options(PyDict::new(py).unbind())
+    }
+
+    fn set<'py>(&self, py: Python<'py>, key: &str, value: impl IntoPyObject<'py>) -> PyResult<()> {
+        self.0.bind(py).set_item(key, value)
+    }
+
+    fn get<'py, T: FromPyObject<'py>>(&self, py: Python<'py>, key: &str) -> PyResult<Option<T>> {
+        self.0
+            .bind(py)
+            .get_item(key)?
+            .map(|v| v.extract())
+            .transpose()
+    }
+
+    fn repr(&self, py: Python<'_>, name: &str, first: Option<String>) -> PyResult<String> {
+        let mut parts: Vec<String> = first.into_iter().collect();
+        for (k, v) in self.0.bind(py) {
+            parts.push(format!("{}={}", k, v.repr()?));
+        }
+        Ok(format!("{}({})", name, parts.join(", ")))
+    }
+}
+
+/// Chainable alternative to the `SuperMinHasher` constructor:
+/// `SuperMinHasherBuilder(128).token_mode("word").n_gram(3).build()`.
+/// `build()` also rejects option combinations that would silently do
+/// something other than intended.
+#[pyclass(frozen)]
+pub struct SuperMinHasherBuilder {
+    size: usize,
+    options: Options,
+}
+
+impl SuperMinHasherBuilder {
+    fn chain<'py, T: IntoPyObject<'py>>(
+        slf: PyRef<'py, Self>,
+        key: &str,
+        value: T,
+    ) -> PyResult<PyRef<'py, Self>> {
+        slf.options.set(slf.py(), key, value)?;
+        Ok(slf)
+    }
+
+    fn validate(&self, py: Python<'_>) -> PyResult<()> {
+        let options = &self.options;
+        if options.get::<String>(py, "token_mode")?.as_deref() == Some("word")
+            && options.get::<usize>(py, "n_gram")?.is_none()
+        {
+            return Err(PyValueError::new_err(
+                "token_mode('word') needs an explicit n_gram; the default of 5 is meant for characters",
+            ));
+        }
+        if options
+            .get::<Option<String>>(py, "punct_pattern")?
+            .flatten()
+            .is_some()
+            && options.get::<bool>(py, "punct_norm")? == Some(false)
+        {
+            return Err(PyValueError::new_err(
+                "punct_pattern has no effect with punct_norm(False)",
+            ));
+        }
+        let zh_variant = options.get::<Option<String>>(py, "zh_variant")?.flatten();
+        if zh_variant.is_some_and(|v| v != "none")
+            && options.get::<bool>(py, "zh_conv")? == Some(false)
+        {
+            return Err(PyValueError::new_err(
+                "zh_variant conflicts with zh_conv(False); use zh_variant('none') to disable conversion",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[pymethods]
+impl SuperMinHasherBuilder {
+    #[new]
+    fn new(py: Python<'_>, size: usize) -> Self {
+        SuperMinHasherBuilder {
+            size,
+            options: Options::new(py),
+        }
+    }
+
+    fn n_gram(slf: PyRef<'_, Self>, n_gram: usize) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "n_gram", n_gram)
+    }
+
+    fn lowercase(slf: PyRef<'_, Self>, enabled: bool) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "lowercase", enabled)
+    }
+
+    fn unicode_normalize(slf: PyRef<'_, Self>, enabled: bool) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "unicode_normalize", enabled)
+    }
+
+    fn zh_conv(slf: PyRef<'_, Self>, enabled: bool) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "zh_conv", enabled)
+    }
+
+    fn zh_variant(slf: PyRef<'_, Self>, variant: String) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "zh_variant", variant)
+    }
+
+    fn punct_norm(slf: PyRef<'_, Self>, enabled: bool) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "punct_norm", enabled)
+    }
+
+    fn punct_pattern(slf: PyRef<'_, Self>, pattern: String) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "punct_pattern", pattern)
+    }
+
+    fn track_distinct(slf: PyRef<'_, Self>, enabled: bool) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "track_distinct", enabled)
+    }
+
+    fn short_text(slf: PyRef<'_, Self>, policy: String) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "short_text", policy)
+    }
+
+    fn anchor_ends(slf: PyRef<'_, Self>, enabled: bool) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "anchor_ends", enabled)
+    }
+
+    fn keep_chars(slf: PyRef<'_, Self>, pattern: String) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "keep_chars", pattern)
+    }
+
+    fn token_mode(slf: PyRef<'_, Self>, mode: String) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "token_mode", mode)
+    }
+
+    fn strip_accents(slf: PyRef<'_, Self>, enabled: bool) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "strip_accents", enabled)
+    }
+
+    fn stopwords(slf: PyRef<'_, Self>, words: HashSet<String>) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "stopwords", words)
+    }
+
+    fn strip_html(slf: PyRef<'_, Self>, enabled: bool) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "strip_html", enabled)
+    }
+
+    fn mask_urls(slf: PyRef<'_, Self>, enabled: bool) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "mask_urls", enabled)
+    }
+
+    fn mask_emails(slf: PyRef<'_, Self>, enabled: bool) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "mask_emails", enabled)
+    }
+
+    fn strip_emoji(slf: PyRef<'_, Self>, enabled: bool) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "strip_emoji", enabled)
+    }
+
+    fn kana_fold(slf: PyRef<'_, Self>, enabled: bool) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "kana_fold", enabled)
+    }
+
+    fn word_n_gram(slf: PyRef<'_, Self>, n_gram: usize) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "word_n_gram", n_gram)
+    }
+
+    fn word_weight(slf: PyRef<'_, Self>, weight: u32) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "word_weight", weight)
+    }
+
+    fn cache_size(slf: PyRef<'_, Self>, size: usize) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "cache_size", size)
+    }
+
+    fn seed(slf: PyRef<'_, Self>, seed: u64) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "seed", seed)
+    }
+
+    fn hasher(slf: PyRef<'_, Self>, name: String) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "hasher", name)
+    }
+
+    fn precision(slf: PyRef<'_, Self>, precision: String) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "precision", precision)
+    }
+
+    fn max_text_bytes(slf: PyRef<'_, Self>, max: usize) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "max_text_bytes", max)
+    }
+
+    fn max_word_len(slf: PyRef<'_, Self>, max: usize) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "max_word_len", max)
+    }
+
+    fn max_char_run(slf: PyRef<'_, Self>, max: usize) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "max_char_run", max)
+    }
+
+    fn preprocessor<'py>(
+        slf: PyRef<'py, Self>,
+        callable: Bound<'py, PyAny>,
+    ) -> PyResult<PyRef<'py, Self>> {
+        Self::chain(slf, "preprocessor", callable)
+    }
+
+    fn build<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, SuperMinHasher>> {
+        self.validate(py)?;
+        Ok(py
+            .get_type::<SuperMinHasher>()
+            .call((self.size,), Some(self.options.0.bind(py)))?
+            .downcast_into()?)
+    }
+
+    fn __repr__(&self, py: Python<'_>) -> PyResult<String> {
+        self.options
+            .repr(py, "SuperMinHasherBuilder", Some(self.size.to_string()))
+    }
+}
+
+/// Chainable alternative to the `LSH` constructor. `build()` makes an `LSH`;
+/// `build_for(hasher)` makes a `SuperMinHasherLSH` from these index options
+/// and a `SuperMinHasherBuilder`.
+#[pyclass(frozen)]
+pub struct LSHBuilder {
+    options: Options,
+}
+
+impl LSHBuilder {
+    fn chain<'py, T: IntoPyObject<'py>>(
+        slf: PyRef<'py, Self>,
+        key: &str,
+        value: T,
+    ) -> PyResult<PyRef<'py, Self>> {
+        slf.options.set(slf.py(), key, value)?;
+        Ok(slf)
+    }
+}
+
+/// Options only `SuperMinHasherLSH` understands.
+const TEXT_INDEX_OPTIONS: [&str; 3] = ["localize", "affix_len", "store_text"];
+
+#[pymethods]
+impl LSHBuilder {
+    #[new]
+    fn new(py: Python<'_>) -> Self {
+        LSHBuilder {
+            options: Options::new(py),
+        }
+    }
+
+    fn fingerprint(slf: PyRef<'_, Self>, fingerprint: u64) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "fingerprint", fingerprint)
+    }
+
+    fn bits(slf: PyRef<'_, Self>, bits: u32) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "bits", bits)
+    }
+
+    fn key_bytes(slf: PyRef<'_, Self>, key_bytes: usize) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "key_bytes", key_bytes)
+    }
+
+    fn boilerplate_fraction(slf: PyRef<'_, Self>, fraction: f64) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "boilerplate_fraction", fraction)
+    }
+
+    fn bucket_slots(slf: PyRef<'_, Self>, slots: usize) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "bucket_slots", slots)
+    }
+
+    fn hashed_buckets(slf: PyRef<'_, Self>, hashed: bool) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "hashed_buckets", hashed)
+    }
+
+    fn max_memory_bytes(slf: PyRef<'_, Self>, bytes: usize) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "max_memory_bytes", bytes)
+    }
+
+    fn localize(slf: PyRef<'_, Self>, enabled: bool) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "localize", enabled)
+    }
+
+    fn affix_len(slf: PyRef<'_, Self>, len: usize) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "affix_len", len)
+    }
+
+    fn store_text(slf: PyRef<'_, Self>, which: String) -> PyResult<PyRef<'_, Self>> {
+        Self::chain(slf, "store_text", which)
+    }
+
+    fn build<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, LSH>> {
+        let options = self.options.0.bind(py);
+        for key in TEXT_INDEX_OPTIONS {
+            if options.contains(key)? {
+                return Err(PyValueError::new_err(format!(
+                    "{} only applies to text indexes; use build_for(hasher)",
+                    key
+                )));
+            }
+        }
+        Ok(py
+            .get_type::<LSH>()
+            .call((), Some(options))?
+            .downcast_into()?)
+    }
+
+    /// A `SuperMinHasherLSH` with the hasher options of `hasher` and the
+    /// index options of this builder.
+    fn build_for<'py>(
+        &self,
+        py: Python<'py>,
+        hasher: &SuperMinHasherBuilder,
+    ) -> PyResult<Bound<'py, SuperMinHasherLSH>> {
+        hasher.validate(py)?;
+        let options = self.options.0.bind(py);
+        if options.contains("fingerprint")? {
+            return Err(PyValueError::new_err(
+                "fingerprint is derived from the hasher in build_for",
+            ));
+        }
+        let kwargs = hasher.options.0.bind(py).copy()?;
+        if kwargs.contains("precision")? {
+            return Err(PyValueError::new_err(
+                "precision does not apply to indexes, which store f32 signatures",
+            ));
+        }
+        kwargs.update(options.as_mapping())?;
+        Ok(py
+            .get_type::<SuperMinHasherLSH>()
+            .call((hasher.size,), Some(&kwargs))?
+            .downcast_into()?)
+    }
+
+    fn __repr__(&self, py: Python<'_>) -> PyResult<String> {
+        self.options.repr(py, "LSHBuilder", None)
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::BTreeMap;
+use std::hash::Hasher;
+
+use fnv::{FnvHashMap, FnvHasher};
+
+/// Signatures of recently sketched texts, keyed on a hash of the raw text
+/// and its length, evicting the least recently used once `capacity` texts
+/// are held.
+pub(crate) struct SignatureCache {
+    capacity: usize,
+    entries: FnvHashMap<(u64, usize), Cached>,
+    /// Last use -> key, oldest first.
+    order: BTreeMap<u64, (u64, usize)>,
+    clock: u64,
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+}
+
+struct Cached {
+    used: u64,
+    signature: Vec<f32>,
+    shingles: usize,
+}
+
+impl SignatureCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        SignatureCache {
+            capacity,
+            entries: FnvHashMap::default(),
+            order: BTreeMap::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub(crate) fn key(text: &str) -> (u64, usize) {
+        let mut h = FnvHasher::default();
+        h.write(text.as_bytes());
+        (h.finish(), text.len())
+    }
+
+    /// The signature and shingle count cached for `key`, marking it used.
+    pub(crate) fn get(&mut self, key: (u64, usize)) -> Option<(Vec<f32>, usize)> {
+        self.clock += 1;
+        let Some(cached) = self.entries.get_mut(&key) else {
+            self.misses += 1;
+            return None;
+        };
+        self.order.remove(&cached.used);
+        cached.used = self.clock;
+        self.order.insert(self.clock, key);
+        self.hits += 1;
+        Some((cached.signature.clone(), cached.shingles))
+    }
+
+    pub(crate) fn insert(&mut self, key: (u64, usize), signature: &[f32], shingles: usize) {
+        if self.entries.len() == self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        self.order.insert(self.clock, key);
+        let cached = Cached {
+            used: self.clock,
+            signature: signature.to_vec(),
+            shingles,
+        };
+        if let Some(replaced) = self.entries.insert(key, cached) {
+            self.order.remove(&replaced.used);
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
@@ -0,0 +1,89 @@
+/// Union-find over entry indices. The root of every set is its smallest
+/// index, so a cluster label only changes when the cluster merges with an
+/// older one.
+#[derive(Clone, Default)]
+pub(crate) struct UnionFind {
+    parent: Vec<usize>,
+    members: Vec<Vec<usize>>,
+}
+
+impl UnionFind {
+    pub(crate) fn push(&mut self) -> usize {
+        let i = self.parent.len();
+        self.parent.push(i);
+        self.members.push(vec![i]);
+        i
+    }
+
+    pub(crate) fn find(&self, mut i: usize) -> usize {
+        while self.parent[i] != i {
+            i = self.parent[i];
+        }
+        i
+    }
+
+    pub(crate) fn union(&mut self, a: usize, b: usize) {
+        let (ra, rb) = (self.find(a), self.find(b));
+        if ra == rb {
+            return;
+        }
+        let (root, child) = if ra < rb { (ra, rb) } else { (rb, ra) };
+        let mut moved = std::mem::take(&mut self.members[child]);
+        for &m in &moved {
+            self.parent[m] = root;
+        }
+        let members = &mut self.members[root];
+        if members.len() < moved.len() {
+            std::mem::swap(members, &mut moved);
+        }
+        members.extend(moved);
+    }
+
+    pub(crate) fn members(&self, i: usize) -> &[usize] {
+        &self.members[self.find(i)]
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.parent.len()
+    }
+
+    /// Descriptions of broken invariants: parents out of range or larger
+    /// than their child, and member lists that disagree with the roots.
+    pub(crate) fn problems(&self) -> Vec<String> {
+        let n = self.parent.len();
+        let mut problems = Vec::new();
+        for (i, &p) in self.parent.iter().enumerate() {
+            if p >= n || p > i {
+                problems.push(format!("cluster parent {} of entry {} is invalid", p, i));
+            }
+        }
+        if !problems.is_empty() {
+            return problems;
+        }
+        let mut listed = 0;
+        for (root, members) in self.members.iter().enumerate() {
+            if !members.is_empty() && self.parent[root] != root {
+                problems.push(format!(
+                    "entry {} lists cluster members but is no root",
+                    root
+                ));
+            }
+            for &m in members {
+                if m >= n || self.find(m) != root {
+                    problems.push(format!(
+                        "cluster {} lists entry {} of another cluster",
+                        root, m
+                    ));
+                }
+            }
+            listed += members.len();
+        }
+        if listed != n {
+            problems.push(format!(
+                "clusters list {} members for {} entries",
+                listed, n
+            ));
+        }
+        problems
+    }
+}
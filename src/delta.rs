@@ -0,0 +1,269 @@
+use fnv::FnvHashMap;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+const MAGIC: &[u8; 4] = b"W6SD";
+const VERSION: u8 = 5;
+
+/// One index entry as exchanged between instances.
+pub(crate) struct DeltaEntry {
+    pub(crate) seq: u64,
+    pub(crate) id: String,
+    pub(crate) timestamp: f64,
+    pub(crate) weight: f64,
+    pub(crate) language: Option<String>,
+    pub(crate) shingles: Option<usize>,
+    pub(crate) metadata: FnvHashMap<String, String>,
+    pub(crate) text: Option<String>,
+    pub(crate) signature: Vec<f32>,
+}
+
+/// A single mutation of an index.
+pub(crate) enum Record {
+    Add(DeltaEntry),
+    /// Removal of the entry with this id.
+    Remove(String),
+}
+
+const OP_ADD: u8 = 0;
+const OP_REMOVE: u8 = 1;
+
+pub(crate) struct Delta {
+    pub(crate) fingerprint: Option<u64>,
+    pub(crate) records: Vec<Record>,
+}
+
+/// Encodes a delta. All integers and floats are little-endian:
+///
+/// ```text
+/// "W6SD" version:u8 has_fingerprint:u8 [fingerprint:u64] width:u32 count:u32
+/// header_crc:u32 count * (op:u8 body record_crc:u32)
+/// add body: seq:u64 id:str timestamp:f64 weight:f64 has_language:u8 [language:str]
+///           has_shingles:u8 [shingles:u64] n_metadata:u32 n_metadata * (key:str value:str)
+///           has_text:u8 [text:str] width * value:f32
+/// remove body: id:str
+/// ```
+///
+/// where `str` is a u32 byte length followed by UTF-8 bytes. Each CRC-32
+/// covers the bytes of its section, from the start of the header or the op
+/// byte up to the checksum itself.
+pub(crate) fn encode(delta: &Delta) -> Vec<u8> {
+    let width = delta
+        .records
+        .iter()
+        .find_map(|record| match record {
+            Record::Add(entry) => Some(entry.signature.len()),
+            Record::Remove(_) => None,
+        })
+        .unwrap_or(0);
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+    match delta.fingerprint {
+        Some(fingerprint) => {
+            out.push(1);
+            out.extend_from_slice(&fingerprint.to_le_bytes());
+        }
+        None => out.push(0),
+    }
+    out.extend_from_slice(&(width as u32).to_le_bytes());
+    out.extend_from_slice(&(delta.records.len() as u32).to_le_bytes());
+    seal(&mut out, 0);
+    for record in &delta.records {
+        let start = out.len();
+        match record {
+            Record::Add(entry) => {
+                out.push(OP_ADD);
+                encode_entry(&mut out, entry);
+            }
+            Record::Remove(id) => {
+                out.push(OP_REMOVE);
+                write_str(&mut out, id);
+            }
+        }
+        seal(&mut out, start);
+    }
+    out
+}
+
+/// Appends the CRC-32 of `out[start..]`.
+fn seal(out: &mut Vec<u8>, start: usize) {
+    let crc = crc32fast::hash(&out[start..]);
+    out.extend_from_slice(&crc.to_le_bytes());
+}
+
+fn encode_entry(out: &mut Vec<u8>, entry: &DeltaEntry) {
+    out.extend_from_slice(&entry.seq.to_le_bytes());
+    write_str(out, &entry.id);
+    out.extend_from_slice(&entry.timestamp.to_le_bytes());
+    out.extend_from_slice(&entry.weight.to_le_bytes());
+    match &entry.language {
+        Some(language) => {
+            out.push(1);
+            write_str(out, language);
+        }
+        None => out.push(0),
+    }
+    match entry.shingles {
+        Some(shingles) => {
+            out.push(1);
+            out.extend_from_slice(&(shingles as u64).to_le_bytes());
+        }
+        None => out.push(0),
+    }
+    out.extend_from_slice(&(entry.metadata.len() as u32).to_le_bytes());
+    let mut metadata: Vec<_> = entry.metadata.iter().collect();
+    metadata.sort();
+    for (k, v) in metadata {
+        write_str(out, k);
+        write_str(out, v);
+    }
+    match &entry.text {
+        Some(text) => {
+            out.push(1);
+            write_str(out, text);
+        }
+        None => out.push(0),
+    }
+    for v in &entry.signature {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> PyResult<Delta> {
+    let mut r = Reader { bytes, pos: 0 };
+    if r.take(4)? != MAGIC {
+        return Err(PyValueError::new_err("not a w6sketch delta"));
+    }
+    let version = r.u8()?;
+    if version != VERSION {
+        return Err(PyValueError::new_err(format!(
+            "unsupported delta version {}",
+            version
+        )));
+    }
+    let fingerprint = if r.u8()? != 0 { Some(r.u64()?) } else { None };
+    let width = r.u32()? as usize;
+    let count = r.u32()? as usize;
+    r.verify(0, "header")?;
+    let mut records = Vec::with_capacity(count.min(bytes.len()));
+    for n in 0..count {
+        let offset = r.pos;
+        let record = match r.u8()? {
+            OP_ADD => Record::Add(decode_entry(&mut r, width)?),
+            OP_REMOVE => Record::Remove(r.str()?),
+            op => {
+                return Err(PyValueError::new_err(format!(
+                    "unknown delta op {} at byte offset {}",
+                    op, offset
+                )))
+            }
+        };
+        r.verify(offset, &format!("record {}", n))?;
+        records.push(record);
+    }
+    if r.pos != bytes.len() {
+        return Err(PyValueError::new_err("trailing bytes after delta"));
+    }
+    Ok(Delta {
+        fingerprint,
+        records,
+    })
+}
+
+fn decode_entry(r: &mut Reader, width: usize) -> PyResult<DeltaEntry> {
+    let seq = r.u64()?;
+    let id = r.str()?;
+    let timestamp = f64::from_bits(r.u64()?);
+    let weight = f64::from_bits(r.u64()?);
+    let language = if r.u8()? != 0 { Some(r.str()?) } else { None };
+    let shingles = if r.u8()? != 0 {
+        Some(r.u64()? as usize)
+    } else {
+        None
+    };
+    let n_metadata = r.u32()? as usize;
+    let mut metadata = FnvHashMap::default();
+    for _ in 0..n_metadata {
+        let k = r.str()?;
+        metadata.insert(k, r.str()?);
+    }
+    let text = if r.u8()? != 0 { Some(r.str()?) } else { None };
+    let signature = (0..width)
+        .map(|_| Ok(f32::from_bits(r.u32()?)))
+        .collect::<PyResult<Vec<f32>>>()?;
+    Ok(DeltaEntry {
+        seq,
+        id,
+        timestamp,
+        weight,
+        language,
+        shingles,
+        metadata,
+        text,
+        signature,
+    })
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> PyResult<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| {
+                PyValueError::new_err(format!("delta truncated at byte offset {}", self.pos))
+            })?;
+        let out = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(out)
+    }
+
+    /// Reads a section checksum and compares it with the CRC-32 of the bytes
+    /// from `start` up to the checksum.
+    fn verify(&mut self, start: usize, section: &str) -> PyResult<()> {
+        let actual = crc32fast::hash(&self.bytes[start..self.pos]);
+        let expected = self.u32()?;
+        if actual != expected {
+            return Err(PyValueError::new_err(format!(
+                "delta checksum mismatch in {} (bytes {}..{}): expected {:#010x}, got {:#010x}",
+                section,
+                start,
+                self.pos - 4,
+                expected,
+                actual
+            )));
+        }
+        Ok(())
+    }
+
+    fn u8(&mut self) -> PyResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> PyResult<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> PyResult<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> PyResult<String> {
+        let len = self.u32()? as usize;
+        let offset = self.pos;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| {
+            PyValueError::new_err(format!("invalid UTF-8 string at byte offset {}", offset))
+        })
+    }
+}
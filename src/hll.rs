@@ -0,0 +1,71 @@
+/// HyperLogLog distinct-count estimator over pre-computed 64-bit hashes.
+pub(crate) struct HyperLogLog {
+    registers: Vec<u8>,
+    precision: u32,
+}
+
+/// Finalizer from MurmurHash3, so that weakly mixed input hashes such as
+/// FNV still spread evenly over the registers.
+pub(crate) fn mix(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+impl HyperLogLog {
+    pub(crate) fn new(precision: u32) -> Self {
+        HyperLogLog {
+            registers: vec![0; 1 << precision],
+            precision,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, hash: u64) {
+        let h = mix(hash);
+        let index = (h >> (64 - self.precision)) as usize;
+        let rank = ((h << self.precision) | (1 << (self.precision - 1))).leading_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub(crate) fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let raw = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if raw <= 2.5 * m && zeros > 0 {
+            // Linear counting is far more accurate for small cardinalities.
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        }
+    }
+
+    pub(crate) fn merge(&mut self, other: &HyperLogLog) {
+        for (r, &o) in self.registers.iter_mut().zip(&other.registers) {
+            *r = (*r).max(o);
+        }
+    }
+
+    pub(crate) fn registers(&self) -> &[u8] {
+        &self.registers
+    }
+
+    /// Restores registers saved with `registers`. Returns false, leaving the
+    /// estimator unchanged, when the length does not match the precision.
+    pub(crate) fn load_registers(&mut self, registers: &[u8]) -> bool {
+        if registers.len() != self.registers.len() {
+            return false;
+        }
+        self.registers.copy_from_slice(registers);
+        true
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.registers.fill(0);
+    }
+}
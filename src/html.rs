@@ -0,0 +1,182 @@
+/// Removes HTML markup from text fed to it piece by piece: tags become a
+/// space, comments and the contents of `script` and `style` elements are
+/// dropped, and the common character references are decoded. Any split of
+/// the input gives the same output, so it can run ahead of chunked reads.
+#[derive(Default)]
+pub(crate) struct HtmlStripper {
+    state: State,
+}
+
+#[derive(Default)]
+enum State {
+    #[default]
+    Text,
+    /// Just after a `<`, which only opens a tag when followed by a letter,
+    /// `/`, `!` or `?`.
+    Open,
+    Tag {
+        name: String,
+        naming: bool,
+        quote: Option<char>,
+    },
+    Comment {
+        dashes: usize,
+    },
+    /// Inside `script` or `style`, `matched` characters into its end tag.
+    Raw {
+        end: &'static str,
+        matched: usize,
+    },
+    Reference(String),
+}
+
+/// Longest character reference looked at, `&` included.
+const MAX_REFERENCE: usize = 10;
+
+fn decode_reference(name: &str) -> Option<char> {
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{a0}'),
+        _ => {
+            let code = name.strip_prefix('#')?;
+            let code = match code.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => code.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+impl HtmlStripper {
+    pub(crate) fn feed(&mut self, input: &str, out: &mut String) {
+        for c in input.chars() {
+            self.step(c, out);
+        }
+    }
+
+    /// Flushes text held back at the end of the input. Unclosed tags,
+    /// comments and script or style elements are dropped.
+    pub(crate) fn finish(self, out: &mut String) {
+        match self.state {
+            State::Open => out.push('<'),
+            State::Reference(reference) => out.push_str(&reference),
+            _ => {}
+        }
+    }
+
+    fn step(&mut self, c: char, out: &mut String) {
+        match &mut self.state {
+            State::Text => match c {
+                '<' => self.state = State::Open,
+                '&' => self.state = State::Reference("&".to_string()),
+                c => out.push(c),
+            },
+            State::Open => {
+                if c.is_alphabetic() || matches!(c, '/' | '!' | '?') {
+                    self.state = State::Tag {
+                        name: c.to_string(),
+                        naming: true,
+                        quote: None,
+                    };
+                } else {
+                    out.push('<');
+                    self.state = State::Text;
+                    self.step(c, out);
+                }
+            }
+            State::Tag {
+                name,
+                naming,
+                quote,
+            } => {
+                if let Some(q) = *quote {
+                    if c == q {
+                        *quote = None;
+                    }
+                    return;
+                }
+                if *naming && name == "!--" {
+                    self.state = State::Comment { dashes: 0 };
+                    self.step(c, out);
+                    return;
+                }
+                match c {
+                    '>' => {
+                        out.push(' ');
+                        let name = name.to_ascii_lowercase();
+                        self.state = match name.as_str() {
+                            "script" => State::Raw {
+                                end: "</script",
+                                matched: 0,
+                            },
+                            "style" => State::Raw {
+                                end: "</style",
+                                matched: 0,
+                            },
+                            _ => State::Text,
+                        };
+                    }
+                    '"' | '\'' if !*naming => *quote = Some(c),
+                    c if *naming && !c.is_whitespace() && c != '/' => name.push(c),
+                    _ => *naming = false,
+                }
+            }
+            State::Comment { dashes } => match c {
+                '-' => *dashes += 1,
+                '>' if *dashes >= 2 => {
+                    out.push(' ');
+                    self.state = State::Text;
+                }
+                _ => *dashes = 0,
+            },
+            State::Raw { end, matched } => {
+                let expected = end[*matched..].chars().next().unwrap();
+                if c.eq_ignore_ascii_case(&expected) {
+                    *matched += 1;
+                    if *matched == end.len() {
+                        self.state = State::Tag {
+                            name: end[1..].to_string(),
+                            naming: false,
+                            quote: None,
+                        };
+                    }
+                } else {
+                    *matched = usize::from(c == '<');
+                }
+            }
+            State::Reference(reference) => {
+                if c == ';' {
+                    match decode_reference(&reference[1..]) {
+                        Some(decoded) => out.push(decoded),
+                        None => {
+                            out.push_str(reference);
+                            out.push(';');
+                        }
+                    }
+                    self.state = State::Text;
+                } else if (c.is_ascii_alphanumeric() || c == '#') && reference.len() < MAX_REFERENCE
+                {
+                    reference.push(c);
+                } else {
+                    out.push_str(reference);
+                    self.state = State::Text;
+                    self.step(c, out);
+                }
+            }
+        }
+    }
+}
+
+/// `text` with its HTML markup removed, see `HtmlStripper`.
+pub(crate) fn strip_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut stripper = HtmlStripper::default();
+    stripper.feed(text, &mut out);
+    stripper.finish(&mut out);
+    out
+}
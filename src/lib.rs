@@ -1,12 +1,52 @@
+mod audit;
+mod bbit;
+mod builder;
+mod cache;
+mod cluster;
+mod delta;
+mod hll;
+mod html;
+mod metrics;
 mod minhash;
+mod multifield;
+mod partial;
+mod pool;
+mod result;
+mod sampling;
+mod signature;
+mod simulate;
+mod spill;
+mod streaming;
+mod weighted;
 
 use pyo3::prelude::*;
 
 #[pymodule]
 fn w6sketch(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<minhash::SuperMinHasher>()?;
+    m.add_class::<minhash::SketchSession>()?;
     m.add_class::<minhash::SuperMinHasherLSH>()?;
     m.add_class::<minhash::LSH>()?;
+    m.add_class::<minhash::LSHReader>()?;
+    m.add_class::<builder::SuperMinHasherBuilder>()?;
+    m.add_class::<builder::LSHBuilder>()?;
+    m.add_class::<multifield::MultiFieldLSH>()?;
+    m.add_class::<streaming::StreamingDeduper>()?;
+    m.add_class::<pool::HasherPool>()?;
+    m.add_class::<result::CheckResult>()?;
+    m.add_class::<weighted::WeightedMinHasher>()?;
     m.add_function(wrap_pyfunction!(minhash::is_release_build, m).unwrap())?;
+    m.add_function(wrap_pyfunction!(simulate::simulate, m).unwrap())?;
+    m.add_function(wrap_pyfunction!(metrics::matching_fraction, m).unwrap())?;
+    m.add_function(wrap_pyfunction!(metrics::jaccard_estimate, m).unwrap())?;
+    m.add_function(wrap_pyfunction!(metrics::jaccard, m).unwrap())?;
+    m.add_function(wrap_pyfunction!(metrics::hamming_distance, m).unwrap())?;
+    m.add_function(wrap_pyfunction!(metrics::cosine_similarity, m).unwrap())?;
+    m.add_function(wrap_pyfunction!(metrics::intersection_size, m).unwrap())?;
+    m.add_function(wrap_pyfunction!(metrics::union_size, m).unwrap())?;
+    m.add_function(wrap_pyfunction!(metrics::slot_agreement, m).unwrap())?;
+    m.add_function(wrap_pyfunction!(metrics::drift_stats, m).unwrap())?;
+    m.add_function(wrap_pyfunction!(signature::signature_to_bytes, m).unwrap())?;
+    m.add_function(wrap_pyfunction!(signature::signature_from_bytes, m).unwrap())?;
     Ok(())
 }
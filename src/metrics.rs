@@ -0,0 +1,339 @@
+use crate::bbit::slot_code;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use pyo3::IntoPyObjectExt;
+
+/// The second operand of the metric functions: one signature, or a
+/// sequence of signatures (a list of lists or a 2-D array) compared row by
+/// row.
+#[derive(FromPyObject)]
+pub(crate) enum Operand {
+    One(Vec<f32>),
+    Many(Vec<Vec<f32>>),
+}
+
+fn check_lengths(a: &[f32], b: &[f32]) -> PyResult<()> {
+    if a.is_empty() {
+        return Err(PyValueError::new_err("signatures must not be empty"));
+    }
+    if a.len() != b.len() {
+        return Err(PyValueError::new_err(format!(
+            "signatures have different lengths: {} and {}",
+            a.len(),
+            b.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Applies `metric` to `a` and `b`, or to `a` and every row of `b`.
+fn apply<'py, T>(
+    py: Python<'py>,
+    a: &[f32],
+    b: Operand,
+    metric: impl Fn(&[f32], &[f32]) -> T,
+) -> PyResult<Bound<'py, PyAny>>
+where
+    T: IntoPyObject<'py>,
+{
+    match b {
+        Operand::One(b) => {
+            check_lengths(a, &b)?;
+            metric(a, &b).into_bound_py_any(py)
+        }
+        Operand::Many(rows) => {
+            for row in &rows {
+                check_lengths(a, row)?;
+            }
+            let values: Vec<T> = rows.iter().map(|row| metric(a, row)).collect();
+            values.into_bound_py_any(py)
+        }
+    }
+}
+
+fn check_bits(bits: Option<u32>) -> PyResult<()> {
+    match bits {
+        Some(bits) if ![1, 2, 4, 8].contains(&bits) => {
+            Err(PyValueError::new_err("bits must be one of 1, 2, 4 or 8"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Slots that differ, comparing only the `bits`-bit codes used by
+/// bit-packed indexes when `bits` is given.
+fn mismatches(a: &[f32], b: &[f32], bits: Option<u32>) -> usize {
+    let differ = |(x, y): (&f32, &f32)| match bits {
+        Some(bits) => slot_code(x.to_bits(), bits) != slot_code(y.to_bits(), bits),
+        None => x != y,
+    };
+    a.iter().zip(b).filter(|&pair| differ(pair)).count()
+}
+
+fn fraction(a: &[f32], b: &[f32], bits: Option<u32>) -> f64 {
+    1.0 - mismatches(a, b, bits) as f64 / a.len() as f64
+}
+
+/// Fraction of slots on which `a` and `b` agree.
+#[pyfunction]
+#[pyo3(signature = (a, b, bits = None))]
+pub fn matching_fraction<'py>(
+    py: Python<'py>,
+    a: Vec<f32>,
+    b: Operand,
+    bits: Option<u32>,
+) -> PyResult<Bound<'py, PyAny>> {
+    check_bits(bits)?;
+    apply(py, &a, b, |a, b| fraction(a, b, bits))
+}
+
+/// Jaccard estimate from two signatures. With `bits`, slots are compared on
+/// their `bits`-bit codes and the agreement p is corrected for random code
+/// collisions as (p - 2^-bits) / (1 - 2^-bits), matching bit-packed indexes.
+#[pyfunction]
+#[pyo3(signature = (a, b, bits = None))]
+pub fn jaccard_estimate<'py>(
+    py: Python<'py>,
+    a: Vec<f32>,
+    b: Operand,
+    bits: Option<u32>,
+) -> PyResult<Bound<'py, PyAny>> {
+    check_bits(bits)?;
+    apply(py, &a, b, |a, b| {
+        let p = fraction(a, b, bits);
+        match bits {
+            Some(bits) => {
+                let collision = 0.5f64.powi(bits as i32);
+                ((p - collision) / (1.0 - collision)).max(0.0)
+            }
+            None => p,
+        }
+    })
+}
+
+/// Slot values of any `finalize` dtype or precision, compared exactly.
+/// Integers are tried first, since Python ints also convert to floats.
+#[derive(FromPyObject)]
+pub(crate) enum Slots {
+    Ints(Vec<u64>),
+    Floats(Vec<f64>),
+}
+
+/// Jaccard estimate of two finalized signatures: the fraction of positions
+/// holding equal values. Unlike `jaccard_estimate`, which reads f32
+/// signatures, the values are compared exactly, so f64 and integer
+/// signatures work too; both must be of the same kind.
+#[pyfunction]
+pub fn jaccard(a: Slots, b: Slots) -> PyResult<f64> {
+    let (len, other, equal) = match (&a, &b) {
+        (Slots::Ints(a), Slots::Ints(b)) => (a.len(), b.len(), count_equal(a, b)),
+        (Slots::Floats(a), Slots::Floats(b)) => (a.len(), b.len(), count_equal(a, b)),
+        _ => {
+            return Err(PyValueError::new_err(
+                "signatures must both hold integers or both hold floats",
+            ))
+        }
+    };
+    if len == 0 {
+        return Err(PyValueError::new_err("signatures must not be empty"));
+    }
+    if len != other {
+        return Err(PyValueError::new_err(format!(
+            "signatures have different lengths: {} and {}",
+            len, other
+        )));
+    }
+    Ok(equal as f64 / len as f64)
+}
+
+fn count_equal<T: PartialEq>(a: &[T], b: &[T]) -> usize {
+    a.iter().zip(b).filter(|(x, y)| x == y).count()
+}
+
+/// Number of differing slots, on `bits`-bit codes when `bits` is given.
+#[pyfunction]
+#[pyo3(signature = (a, b, bits = None))]
+pub fn hamming_distance<'py>(
+    py: Python<'py>,
+    a: Vec<f32>,
+    b: Operand,
+    bits: Option<u32>,
+) -> PyResult<Bound<'py, PyAny>> {
+    check_bits(bits)?;
+    apply(py, &a, b, |a, b| mismatches(a, b, bits))
+}
+
+/// Cosine similarity of the signatures as real vectors, for projection
+/// sketches. Zero vectors have similarity 0.
+#[pyfunction]
+pub fn cosine_similarity<'py>(
+    py: Python<'py>,
+    a: Vec<f32>,
+    b: Operand,
+) -> PyResult<Bound<'py, PyAny>> {
+    apply(py, &a, b, |a, b| {
+        let (mut dot, mut na, mut nb) = (0f64, 0f64, 0f64);
+        for (&x, &y) in a.iter().zip(b) {
+            let (x, y) = (x as f64, y as f64);
+            dot += x * y;
+            na += x * x;
+            nb += y * y;
+        }
+        if na == 0.0 || nb == 0.0 {
+            0.0
+        } else {
+            dot / (na.sqrt() * nb.sqrt())
+        }
+    })
+}
+
+/// Estimated |A ∩ B| and |A ∪ B| of shingle sets of sizes `size_a` and
+/// `size_b` from the Jaccard estimate J of their signatures, by
+/// inclusion-exclusion: |A ∩ B| = J (|A| + |B|) / (1 + J).
+fn overlap_sizes(a: &[f32], b: &[f32], size_a: f64, size_b: f64) -> PyResult<(f64, f64)> {
+    check_lengths(a, b)?;
+    if !(size_a >= 0.0 && size_b >= 0.0) {
+        return Err(PyValueError::new_err("set sizes must be non-negative"));
+    }
+    let j = fraction(a, b, None);
+    let intersection = (j * (size_a + size_b) / (1.0 + j)).min(size_a.min(size_b));
+    Ok((intersection, size_a + size_b - intersection))
+}
+
+/// Estimated number of shingles two documents share, given their signatures
+/// and shingle-set sizes.
+#[pyfunction]
+pub fn intersection_size(a: Vec<f32>, b: Vec<f32>, size_a: f64, size_b: f64) -> PyResult<f64> {
+    Ok(overlap_sizes(&a, &b, size_a, size_b)?.0)
+}
+
+/// Estimated number of distinct shingles of two documents together, given
+/// their signatures and shingle-set sizes.
+#[pyfunction]
+pub fn union_size(a: Vec<f32>, b: Vec<f32>, size_a: f64, size_b: f64) -> PyResult<f64> {
+    Ok(overlap_sizes(&a, &b, size_a, size_b)?.1)
+}
+
+/// Checks that `a` and `b` are non-empty matrices of the same shape, one
+/// signature per row, and returns their slot count.
+fn check_matrices(a: &[Vec<f32>], b: &[Vec<f32>]) -> PyResult<usize> {
+    if a.len() != b.len() {
+        return Err(PyValueError::new_err(format!(
+            "matrices have different row counts: {} and {}",
+            a.len(),
+            b.len()
+        )));
+    }
+    let Some(first) = a.first() else {
+        return Err(PyValueError::new_err("matrices must not be empty"));
+    };
+    for (x, y) in a.iter().zip(b) {
+        check_lengths(first, x)?;
+        check_lengths(first, y)?;
+    }
+    Ok(first.len())
+}
+
+/// For each slot, the fraction of rows on which `a` and `b` agree.
+fn slot_agreements(a: &[Vec<f32>], b: &[Vec<f32>], slots: usize, bits: Option<u32>) -> Vec<f64> {
+    let mut agree = vec![0usize; slots];
+    for (x, y) in a.iter().zip(b) {
+        for (j, (p, q)) in x.iter().zip(y).enumerate() {
+            let same = match bits {
+                Some(bits) => slot_code(p.to_bits(), bits) == slot_code(q.to_bits(), bits),
+                None => p == q,
+            };
+            agree[j] += usize::from(same);
+        }
+    }
+    agree.iter().map(|&n| n as f64 / a.len() as f64).collect()
+}
+
+/// Mean, standard deviation, minimum and maximum of `values`.
+fn summary(values: &[f64]) -> (f64, f64, f64, f64) {
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let variance = values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / n;
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    (mean, variance.sqrt(), min, max)
+}
+
+/// Per-slot agreement of two signature matrices (lists of lists or 2-D
+/// arrays) whose row i sketches the same text: the fraction of rows on
+/// which slot j matches, on `bits`-bit codes when `bits` is given.
+#[pyfunction]
+#[pyo3(signature = (a, b, bits = None))]
+pub fn slot_agreement(
+    py: Python<'_>,
+    a: Vec<Vec<f32>>,
+    b: Vec<Vec<f32>>,
+    bits: Option<u32>,
+) -> PyResult<Vec<f64>> {
+    check_bits(bits)?;
+    let slots = check_matrices(&a, &b)?;
+    Ok(py.allow_threads(|| slot_agreements(&a, &b, slots, bits)))
+}
+
+/// Drift between two signature matrices whose row i sketches the same text,
+/// such as the output of two pipeline versions over one corpus. Returns a
+/// dict with the per-slot agreement (`slot_agreement`) and its `mean`,
+/// `std`, `min`, `max` and `worst_slot`; the per-row agreement summarised
+/// as `row_mean`, `row_min`, `row_max` and `row_quantiles` (at 0.01, 0.1,
+/// 0.5, 0.9 and 0.99); `identical_rows`; and `value_shift`, each slot's mean
+/// value in `b` minus that in `a`. Unchanged sketching gives agreement 1
+/// everywhere; a uniform drop points at a change upstream of hashing, a few
+/// low slots at a change in the hash or slot assignment.
+#[pyfunction]
+#[pyo3(signature = (a, b, bits = None))]
+pub fn drift_stats<'py>(
+    py: Python<'py>,
+    a: Vec<Vec<f32>>,
+    b: Vec<Vec<f32>>,
+    bits: Option<u32>,
+) -> PyResult<Bound<'py, PyDict>> {
+    check_bits(bits)?;
+    let slots = check_matrices(&a, &b)?;
+    let (slot, mut rows, shift) = py.allow_threads(|| {
+        let slot = slot_agreements(&a, &b, slots, bits);
+        let rows: Vec<f64> = a
+            .iter()
+            .zip(&b)
+            .map(|(x, y)| fraction(x, y, bits))
+            .collect();
+        let mut shift = vec![0f64; slots];
+        for (x, y) in a.iter().zip(&b) {
+            for (s, (p, q)) in shift.iter_mut().zip(x.iter().zip(y)) {
+                *s += *q as f64 - *p as f64;
+            }
+        }
+        shift.iter_mut().for_each(|s| *s /= a.len() as f64);
+        (slot, rows, shift)
+    });
+    let (mean, std, min, max) = summary(&slot);
+    let worst_slot = slot.iter().position(|&v| v == min).unwrap_or(0);
+    let (row_mean, _, row_min, row_max) = summary(&rows);
+    let identical_rows = rows.iter().filter(|&&v| v == 1.0).count();
+    rows.sort_by(f64::total_cmp);
+    let quantiles: Vec<f64> = [0.01, 0.1, 0.5, 0.9, 0.99]
+        .iter()
+        .map(|q| rows[((rows.len() - 1) as f64 * q).round() as usize])
+        .collect();
+    let dict = PyDict::new(py);
+    dict.set_item("rows", a.len())?;
+    dict.set_item("slots", slots)?;
+    dict.set_item("slot_agreement", slot)?;
+    dict.set_item("mean", mean)?;
+    dict.set_item("std", std)?;
+    dict.set_item("min", min)?;
+    dict.set_item("max", max)?;
+    dict.set_item("worst_slot", worst_slot)?;
+    dict.set_item("row_mean", row_mean)?;
+    dict.set_item("row_min", row_min)?;
+    dict.set_item("row_max", row_max)?;
+    dict.set_item("row_quantiles", quantiles)?;
+    dict.set_item("identical_rows", identical_rows)?;
+    dict.set_item("value_shift", shift)?;
+    Ok(dict)
+}
@@ -1,23 +1,444 @@
+use crate::audit::AuditLog;
+use crate::bbit::PackedSignatures;
+use crate::cache::SignatureCache;
+use crate::cluster::UnionFind;
+use crate::delta::{Delta, DeltaEntry, Record};
+use crate::hll::{mix, HyperLogLog};
+use crate::html::{strip_html, HtmlStripper};
+use crate::result::CheckResult;
+use crate::spill::{SpillFile, SpillSlot};
 use fnv::{FnvHashMap, FnvHashSet, FnvHasher};
 use lazy_static::lazy_static;
 use probminhash::superminhasher::SuperMinHash;
-use pyo3::exceptions::PyValueError;
+use pyo3::exceptions::{PyTypeError, PyValueError};
 use pyo3::prelude::*;
-use std::hash::BuildHasherDefault;
+use pyo3::types::{PyBytes, PyDict, PyIterator};
+use pyo3::IntoPyObjectExt;
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use twox_hash::XxHash3_64;
+use wyhash::WyHash;
+use zhconv::Variant;
 
 #[pyfunction]
 pub fn is_release_build() -> bool {
     !cfg!(debug_assertions)
 }
 
+/// The entry data is shared with `LSHReader`s behind `Arc`s and written
+/// through `Arc::make_mut`, so a reader costs nothing until the index next
+/// changes that part, which then copies it once.
 #[pyclass]
+#[allow(clippy::upper_case_acronyms)]
 pub struct LSH {
-    candidates: FnvHashMap<Vec<u8>, FnvHashSet<usize>>,
-    hashes: Vec<Vec<Vec<u8>>>,
-    ids: Vec<String>,
-    id_map: FnvHashMap<String, usize>,
+    /// Buckets keyed on the `key_bytes` most significant bytes of a slot,
+    /// see `bucket_key`.
+    candidates: Arc<FnvHashMap<u64, FnvHashSet<usize>>>,
+    key_bytes: usize,
+    /// Whether bucket keys hash the slot index in with the value, so equal
+    /// values only collide in the same slot.
+    hashed_buckets: bool,
+    /// Hash function of the `hashed_buckets` keys, the shingle hasher of the
+    /// configuration the signatures come from.
+    bucket_hasher: ShingleHasher,
+    /// Buckets holding more than this fraction of all entries are ignored by
+    /// queries, see `is_boilerplate`.
+    boilerplate_fraction: Option<f64>,
+    /// When set, only the first this many slots are bucketed; candidates are
+    /// still verified on the whole signature.
+    bucket_slots: Option<usize>,
+    hashes: Arc<Vec<Vec<Vec<u8>>>>,
+    ids: Arc<Vec<String>>,
+    id_map: Arc<FnvHashMap<String, usize>>,
+    entries: Arc<Vec<EntryInfo>>,
+    next_auto_id: u64,
+    next_seq: u64,
+    clusters: Arc<UnionFind>,
+    /// Known duplicate links waiting for one of their ids to be indexed.
+    pending_links: Arc<FnvHashMap<String, Vec<String>>>,
+    width: Option<usize>,
+    fingerprint: Option<u64>,
+    /// Mutations recorded since the last `drain_log`, when enabled.
+    log: Option<Vec<Record>>,
+    /// Rejected inserts and what they matched, when audit mode is enabled.
+    audit: Option<AuditLog>,
+    /// Id and metadata of every rejected insert, for `stats_by`.
+    rejected: Vec<(String, FnvHashMap<String, String>)>,
+    /// In bit-packed mode, the verification codes; `hashes` then holds
+    /// empty placeholders.
+    packed: Option<Arc<PackedSignatures>>,
+    /// Heap bytes `hashes` may hold before the coldest signatures are
+    /// spilled, see `with_max_memory_bytes`.
+    max_memory_bytes: Option<usize>,
+    /// Heap bytes of the signatures held in `hashes`.
+    resident_bytes: usize,
+    /// Signatures moved out of memory, created on the first spill. Spilled
+    /// entries keep an empty placeholder in `hashes`.
+    spill: Option<Arc<SpillFile>>,
 }
 
+/// Per-entry attributes stored next to the signature.
+#[derive(Clone)]
+struct EntryInfo {
+    /// Insertion sequence number, assigned by the index.
+    seq: u64,
+    metadata: FnvHashMap<String, String>,
+    timestamp: f64,
+    /// Number of shingles the signature was built from, when known.
+    shingles: Option<usize>,
+    /// Estimated number of distinct shingles, read off the signature of
+    /// entries with a `shingles` count.
+    distinct: Option<f64>,
+    /// Document text kept for review, see `StoreText`.
+    text: Option<String>,
+    /// Whether the entry matched others when inserted.
+    duplicate: bool,
+    /// Number of later inserts that matched this entry.
+    matched: usize,
+    /// Importance given at insert, see `LSH.check_and_add`.
+    weight: f64,
+    /// Total weight of rejected inserts whose best match this entry was.
+    absorbed: f64,
+    /// Language tag given at insert, see `CandidateFilter::threshold_for`.
+    language: Option<String>,
+    /// Where the signature is when it was spilled to disk.
+    spilled: Option<SpillSlot>,
+    /// `next_seq` when an insert last added or matched the entry. The
+    /// lowest spill first.
+    last_used: u64,
+}
+
+impl EntryInfo {
+    fn new(metadata: Option<FnvHashMap<String, String>>, timestamp: Option<f64>) -> Self {
+        EntryInfo {
+            seq: 0,
+            metadata: metadata.unwrap_or_default(),
+            timestamp: timestamp.unwrap_or_else(now),
+            shingles: None,
+            distinct: None,
+            text: None,
+            duplicate: false,
+            matched: 0,
+            weight: 1.0,
+            absorbed: 0.0,
+            language: None,
+            spilled: None,
+            last_used: 0,
+        }
+    }
+
+    fn with_weight(mut self, weight: Option<f64>) -> PyResult<Self> {
+        if let Some(weight) = weight {
+            if !(weight >= 0.0 && weight.is_finite()) {
+                return Err(PyValueError::new_err(
+                    "weight must be a non-negative number",
+                ));
+            }
+            self.weight = weight;
+        }
+        Ok(self)
+    }
+
+    fn with_language(mut self, language: Option<String>) -> Self {
+        self.language = language;
+        self
+    }
+}
+
+/// Restricts which indexed entries are considered as candidates, either by
+/// requiring exact metadata values or by calling a Python predicate on the
+/// entry's metadata dict.
+enum MetadataFilter<'py> {
+    Equals(FnvHashMap<String, String>),
+    Predicate(Bound<'py, PyAny>),
+}
+
+impl<'py> MetadataFilter<'py> {
+    fn from_py(filter: &Bound<'py, PyAny>) -> PyResult<Self> {
+        if let Ok(dict) = filter.downcast::<PyDict>() {
+            Ok(MetadataFilter::Equals(dict.extract()?))
+        } else if filter.is_callable() {
+            Ok(MetadataFilter::Predicate(filter.clone()))
+        } else {
+            Err(PyTypeError::new_err(
+                "filter must be a dict or a callable taking the metadata dict",
+            ))
+        }
+    }
+
+    fn matches(&self, metadata: &FnvHashMap<String, String>) -> PyResult<bool> {
+        match self {
+            MetadataFilter::Equals(expected) => Ok(expected
+                .iter()
+                .all(|(k, v)| metadata.get(k).is_some_and(|m| m == v))),
+            MetadataFilter::Predicate(f) => f.call1((metadata.clone(),))?.is_truthy(),
+        }
+    }
+}
+
+/// Conditions an indexed entry must satisfy before it is verified against a
+/// query, and an optional rescoring of the verified matches. Entries are
+/// identified by their position in the index.
+#[derive(Default)]
+struct CandidateFilter<'py> {
+    metadata: Option<MetadataFilter<'py>>,
+    since: Option<f64>,
+    until: Option<f64>,
+    rerank: Option<Rerank<'py>>,
+    /// When verification has to stop, see `with_time_budget`.
+    deadline: Option<Instant>,
+    /// Set once a query gave up on candidates because of `deadline`.
+    truncated: Cell<bool>,
+    /// Only ids starting with it are verified.
+    id_prefix: Option<String>,
+    /// Verification stops after this many matches, see `with_max_results`.
+    max_results: Option<usize>,
+    /// Set once a query found more than `max_results` matches.
+    overflow: Cell<bool>,
+    /// Language tag -> threshold, see `threshold_for`.
+    language_thresholds: Option<FnvHashMap<String, f64>>,
+    /// Largest ratio of the longer to the shorter shingle count a match may
+    /// have, see `with_max_length_ratio`.
+    max_length_ratio: Option<f64>,
+    /// Shingle count of the query, for `max_length_ratio`.
+    query_shingles: Cell<Option<usize>>,
+}
+
+/// Python callable `(query, candidate_id, similarity) -> float | None` run
+/// on every match that reached the threshold. A float replaces the
+/// similarity, `None` drops the candidate.
+struct Rerank<'py> {
+    callback: Bound<'py, PyAny>,
+    query: Bound<'py, PyAny>,
+}
+
+impl<'py> CandidateFilter<'py> {
+    fn new(
+        metadata: Option<&Bound<'py, PyAny>>,
+        since: Option<f64>,
+        until: Option<f64>,
+    ) -> PyResult<Self> {
+        Ok(CandidateFilter {
+            metadata: metadata.map(MetadataFilter::from_py).transpose()?,
+            since,
+            until,
+            rerank: None,
+            deadline: None,
+            truncated: Cell::new(false),
+            id_prefix: None,
+            max_results: None,
+            overflow: Cell::new(false),
+            language_thresholds: None,
+            max_length_ratio: None,
+            query_shingles: Cell::new(None),
+        })
+    }
+
+    fn with_id_prefix(mut self, prefix: Option<String>) -> Self {
+        self.id_prefix = prefix;
+        self
+    }
+
+    /// Stops verifying candidates once more than `max` matched, keeping the
+    /// first `max` and setting `overflow`. Candidates sharing more buckets
+    /// with the query are verified first. The cap applies before `rerank`.
+    fn with_max_results(mut self, max: Option<usize>) -> Self {
+        self.max_results = max;
+        self
+    }
+
+    /// Stops verifying candidates `ms` milliseconds from now; the matches
+    /// found by then are returned and `truncated` is set.
+    fn with_time_budget(mut self, ms: Option<f64>) -> PyResult<Self> {
+        if let Some(ms) = ms {
+            if !(ms >= 0.0 && ms.is_finite()) {
+                return Err(PyValueError::new_err(
+                    "time_budget_ms must be a non-negative number",
+                ));
+            }
+            self.deadline = Some(Instant::now() + Duration::from_secs_f64(ms / 1000.0));
+        }
+        Ok(self)
+    }
+
+    fn with_language_thresholds(mut self, thresholds: Option<FnvHashMap<String, f64>>) -> Self {
+        self.language_thresholds = thresholds;
+        self
+    }
+
+    /// Drops candidates whose shingle count is more than `ratio` times that
+    /// of the query or less than its `1 / ratio`th, the query's being
+    /// `shingles` or set later with `set_query_shingles`. Entries inserted
+    /// without a count are kept.
+    fn with_max_length_ratio(
+        mut self,
+        ratio: Option<f64>,
+        shingles: Option<usize>,
+    ) -> PyResult<Self> {
+        if let Some(ratio) = ratio {
+            if ratio.is_nan() || ratio < 1.0 {
+                return Err(PyValueError::new_err("max_length_ratio must be at least 1"));
+            }
+        }
+        self.max_length_ratio = ratio;
+        self.query_shingles.set(shingles);
+        Ok(self)
+    }
+
+    fn set_query_shingles(&self, shingles: usize) {
+        self.query_shingles.set(Some(shingles));
+    }
+
+    /// Signature queries have no shingle count of their own to compare.
+    fn check_query_shingles(&self) -> PyResult<()> {
+        if self.max_length_ratio.is_some() && self.query_shingles.get().is_none() {
+            return Err(PyValueError::new_err(
+                "max_length_ratio needs the shingle count of the query",
+            ));
+        }
+        Ok(())
+    }
+
+    fn length_ratio_ok(&self, entry: &EntryInfo) -> bool {
+        match (
+            self.max_length_ratio,
+            self.query_shingles.get(),
+            entry.shingles,
+        ) {
+            (Some(ratio), Some(a), Some(b)) => a.max(b) as f64 <= ratio * a.min(b) as f64,
+            _ => true,
+        }
+    }
+
+    /// The threshold entry `i` has to reach: the one mapped to its language
+    /// tag, or `default` for untagged entries and languages not mapped. Each
+    /// candidate is held to its own, so indexes mixing languages whose
+    /// shingles score differently need no separate indexes.
+    fn threshold_for(&self, lsh: &LSH, i: usize, default: f64) -> f64 {
+        self.language_thresholds
+            .as_ref()
+            .zip(lsh.entries[i].language.as_ref())
+            .and_then(|(thresholds, language)| thresholds.get(language))
+            .copied()
+            .unwrap_or(default)
+    }
+
+    /// Whether a time budget or `max_results` can cut verification short.
+    fn may_stop_early(&self) -> bool {
+        self.deadline.is_some() || self.max_results.is_some()
+    }
+
+    /// Whether the time budget is used up, recording so in `truncated`.
+    fn out_of_time(&self) -> bool {
+        let expired = self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline);
+        if expired {
+            self.truncated.set(true);
+        }
+        expired
+    }
+
+    /// Rescores matches with `callback`, passing it `query` first.
+    fn with_rerank(
+        mut self,
+        callback: Option<&Bound<'py, PyAny>>,
+        query: impl FnOnce() -> PyResult<Bound<'py, PyAny>>,
+    ) -> PyResult<Self> {
+        if let Some(callback) = callback {
+            self.rerank = Some(Rerank {
+                callback: callback.clone(),
+                query: query()?,
+            });
+        }
+        Ok(self)
+    }
+
+    fn rescore(&self, lsh: &LSH, matches: Vec<(usize, f64)>) -> PyResult<Vec<(usize, f64)>> {
+        let Some(rerank) = &self.rerank else {
+            return Ok(matches);
+        };
+        let mut rescored = Vec::with_capacity(matches.len());
+        for (i, similarity) in matches {
+            let score: Option<f64> = rerank
+                .callback
+                .call1((&rerank.query, &lsh.ids[i], similarity))?
+                .extract()?;
+            if let Some(score) = score {
+                rescored.push((i, score));
+            }
+        }
+        Ok(rescored)
+    }
+
+    fn accepts(&self, lsh: &LSH, i: usize) -> PyResult<bool> {
+        if self
+            .id_prefix
+            .as_ref()
+            .is_some_and(|prefix| !lsh.ids[i].starts_with(prefix.as_str()))
+        {
+            return Ok(false);
+        }
+        let entry = &lsh.entries[i];
+        let ts = entry.timestamp;
+        if self.since.is_some_and(|since| ts < since) || self.until.is_some_and(|until| ts > until)
+        {
+            return Ok(false);
+        }
+        if !self.length_ratio_ok(entry) {
+            return Ok(false);
+        }
+        match &self.metadata {
+            Some(filter) => filter.matches(&entry.metadata),
+            None => Ok(true),
+        }
+    }
+}
+
+/// Seconds since the Unix epoch, matching Python's `time.time()`.
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+/// Version of the `to_dict` layout.
+const STATE_FORMAT: u32 = 1;
+
+/// Extracts a required `to_dict` field.
+fn required<'py, T: FromPyObject<'py>>(state: &Bound<'py, PyDict>, key: &str) -> PyResult<T> {
+    state
+        .get_item(key)?
+        .ok_or_else(|| PyValueError::new_err(format!("state is missing {:?}", key)))?
+        .extract()
+}
+
+fn to_bytes(data: &[f32]) -> Vec<Vec<u8>> {
+    data.iter().map(|x| x.to_le_bytes().to_vec()).collect()
+}
+
+/// The signature `to_bytes` made `data_bytes` from.
+fn from_bytes(data_bytes: &[Vec<u8>]) -> Vec<f32> {
+    data_bytes
+        .iter()
+        .map(|y| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&y[..4]);
+            f32::from_le_bytes(bytes)
+        })
+        .collect()
+}
+
+/// Fraction of agreeing slots over the length of `a`, which may be a prefix
+/// of `b`: each slot is an unbiased estimate on its own, so a truncated query
+/// is scored over the slots it actually has.
 fn similarity_threshold(a: &[Vec<u8>], b: &[Vec<u8>]) -> f64 {
     let mut count = 0;
     for i in 0..a.len() {
@@ -28,213 +449,4154 @@ fn similarity_threshold(a: &[Vec<u8>], b: &[Vec<u8>]) -> f64 {
     count as f64 / a.len() as f64
 }
 
-#[pymethods]
+/// Drops every `v[i]` with `removed[i]`.
+fn retain_unflagged<T>(v: &mut Vec<T>, removed: &[bool]) {
+    let mut removed = removed.iter();
+    v.retain(|_| !removed.next().unwrap());
+}
+
+/// Heap bytes a signature takes in `LSH::hashes`.
+fn signature_bytes(data_bytes: &[Vec<u8>]) -> usize {
+    data_bytes
+        .iter()
+        .map(|y| std::mem::size_of::<Vec<u8>>() + y.len())
+        .sum()
+}
+
+/// Bytes `shrink_to_fit` would free from `v`, ignoring allocator rounding.
+fn vec_slack<T>(v: &Vec<T>) -> usize {
+    (v.capacity() - v.len()) * std::mem::size_of::<T>()
+}
+
+/// Capacity left to a hash table of `len` elements by `shrink_to_fit`,
+/// which keeps a power of two of slots at most 7/8 full.
+fn fitted_capacity(len: usize) -> usize {
+    match len {
+        0 => 0,
+        1..=3 => 3,
+        4..=7 => 7,
+        _ => (len * 8 / 7).next_power_of_two() / 8 * 7,
+    }
+}
+
+/// Slots of a hash table `shrink_to_fit` would free.
+fn table_excess(capacity: usize, len: usize) -> usize {
+    capacity.saturating_sub(fitted_capacity(len))
+}
+
+/// Like `vec_slack` for a hash table of `T` elements, counting the control
+/// byte each slot carries.
+fn table_slack<T>(capacity: usize, len: usize) -> usize {
+    table_excess(capacity, len) * (std::mem::size_of::<T>() + 1)
+}
+
+/// Allocated but unused room in an index, see `LSH.fragmentation`.
+#[derive(Default)]
+struct Fragmentation {
+    dead_slots: usize,
+    wasted_bucket_entries: usize,
+    recoverable_bytes: usize,
+}
+
+impl Fragmentation {
+    fn into_dict(self) -> FnvHashMap<String, usize> {
+        let mut report = FnvHashMap::default();
+        report.insert("dead_slots".to_string(), self.dead_slots);
+        report.insert(
+            "wasted_bucket_entries".to_string(),
+            self.wasted_bucket_entries,
+        );
+        report.insert("recoverable_bytes".to_string(), self.recoverable_bytes);
+        report
+    }
+}
+
+/// Smallest bucket `boilerplate_fraction` may ignore.
+const BOILERPLATE_MIN_ENTRIES: usize = 10;
+
+/// Like `similarity_threshold`, over the slots not flagged in `skip`. Zero
+/// when every slot is skipped.
+fn similarity_skipping(a: &[Vec<u8>], b: &[Vec<u8>], skip: &[bool]) -> f64 {
+    let mut count = 0;
+    let mut total = 0;
+    for i in 0..a.len() {
+        if skip[i] {
+            continue;
+        }
+        total += 1;
+        if a[i] == b[i] {
+            count += 1;
+        }
+    }
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 / total as f64
+    }
+}
+
 impl LSH {
-    #[new]
-    fn new() -> Self {
+    pub(crate) fn new(fingerprint: Option<u64>) -> Self {
         LSH {
-            candidates: FnvHashMap::default(),
-            hashes: Vec::new(),
-            ids: Vec::new(),
-            id_map: FnvHashMap::default(),
+            candidates: Arc::default(),
+            key_bytes: 4,
+            hashed_buckets: false,
+            bucket_hasher: ShingleHasher::Fnv,
+            boilerplate_fraction: None,
+            bucket_slots: None,
+            hashes: Arc::default(),
+            ids: Arc::default(),
+            id_map: Arc::default(),
+            entries: Arc::default(),
+            next_auto_id: 0,
+            next_seq: 0,
+            clusters: Arc::default(),
+            pending_links: Arc::default(),
+            width: None,
+            fingerprint,
+            log: None,
+            audit: None,
+            rejected: Vec::new(),
+            packed: None,
+            max_memory_bytes: None,
+            resident_bytes: 0,
+            spill: None,
         }
     }
 
-    fn keys(&self) -> Vec<String> {
-        self.ids.clone()
+    /// Switches an empty index to bit-packed verification.
+    pub(crate) fn with_bits(mut self, bits: Option<u32>) -> PyResult<Self> {
+        self.packed = bits.map(PackedSignatures::new).transpose()?.map(Arc::new);
+        Ok(self)
     }
 
-    fn values(&self) -> Vec<Vec<f32>> {
-        self.hashes
-            .iter()
-            .map(|x| {
-                x.iter()
-                    .map(|y| {
-                        let mut bytes = [0u8; 4];
-                        bytes.copy_from_slice(&y[..4]);
-                        f32::from_le_bytes(bytes)
-                    })
-                    .collect()
-            })
-            .collect()
+    /// Buckets an empty index on fewer bytes of each slot value. Shorter
+    /// keys give fewer, denser buckets and more candidates to verify, but
+    /// never fewer matches.
+    pub(crate) fn with_key_bytes(mut self, key_bytes: usize) -> PyResult<Self> {
+        if !(1..=4).contains(&key_bytes) {
+            return Err(PyValueError::new_err("key_bytes must be between 1 and 4"));
+        }
+        self.key_bytes = key_bytes;
+        Ok(self)
     }
 
-    fn length(&self) -> usize {
-        self.ids.len()
+    /// Keys the buckets of an empty index on a hash of slot index and value,
+    /// so only values equal in the same slot make candidates, as they are
+    /// the only ones verification counts.
+    fn with_hashed_buckets(mut self, hashed: bool) -> Self {
+        self.hashed_buckets = hashed;
+        self
     }
-    #[pyo3(signature = (data, threshold = 0.5))]
-    #[inline]
-    fn check(&self, data: Vec<f32>, threshold: f64) -> FnvHashMap<String, f64> {
-        let data_bytes: Vec<Vec<u8>> = data
-            .iter()
-            .map(|x| {
-                let mut bytes = [0u8; 4];
-                bytes.copy_from_slice(&x.to_le_bytes());
-                bytes.to_vec()
-            })
+
+    /// Hashes the `hashed_buckets` keys of an empty index with `hasher`.
+    fn with_bucket_hasher(mut self, hasher: ShingleHasher) -> Self {
+        self.bucket_hasher = hasher;
+        self
+    }
+
+    /// Buckets an empty index on the first `slots` slots only, so a long
+    /// signature gets the candidate set of a short one but is verified in
+    /// full. Fewer slots mean fewer candidates and a higher chance of
+    /// missing a match.
+    fn with_bucket_slots(mut self, slots: Option<usize>) -> PyResult<Self> {
+        if slots == Some(0) {
+            return Err(PyValueError::new_err("bucket_slots must be greater than 0"));
+        }
+        self.bucket_slots = slots;
+        Ok(self)
+    }
+
+    /// Keeps at most about `bytes` of signatures in memory. Past that, the
+    /// signatures of the entries longest not added or matched by an insert
+    /// move to a temp file until a quarter of the budget is free again, and
+    /// verification reads them back from there. Buckets, ids and entry
+    /// attributes stay in memory, and the space of removed entries is only
+    /// reclaimed with the index.
+    fn with_max_memory_bytes(mut self, bytes: Option<usize>) -> PyResult<Self> {
+        if bytes.is_some() && self.packed.is_some() {
+            return Err(PyValueError::new_err(
+                "max_memory_bytes bounds full signatures and cannot be combined with bits",
+            ));
+        }
+        self.max_memory_bytes = bytes;
+        Ok(self)
+    }
+
+    /// The signature of entry `i`, read back from the spill file if needed.
+    fn hashes_of(&self, i: usize) -> PyResult<Cow<'_, [Vec<u8>]>> {
+        match (self.entries[i].spilled, &self.spill) {
+            (Some(slot), Some(spill)) => Ok(Cow::Owned(spill.read(slot)?)),
+            _ => Ok(Cow::Borrowed(&self.hashes[i])),
+        }
+    }
+
+    /// Spills the coldest signatures once `hashes` outgrows
+    /// `max_memory_bytes`, down to three quarters of it.
+    fn enforce_memory_budget(&mut self) -> PyResult<()> {
+        let Some(budget) = self.max_memory_bytes else {
+            return Ok(());
+        };
+        if self.resident_bytes <= budget {
+            return Ok(());
+        }
+        let mut resident: Vec<usize> = (0..self.ids.len())
+            .filter(|&i| !self.hashes[i].is_empty())
             .collect();
-        let candidates: FnvHashSet<usize> = data_bytes
-            .iter()
-            .flat_map(|x| {
-                if let Some(c) = self.candidates.get(x) {
-                    c.iter().cloned().collect()
-                } else {
-                    vec![]
-                }
+        resident.sort_by_key(|&i| self.entries[i].last_used);
+        let spill = match &self.spill {
+            Some(spill) => Arc::clone(spill),
+            None => Arc::clone(self.spill.insert(Arc::new(SpillFile::create()?))),
+        };
+        let target = budget - budget / 4;
+        let hashes = Arc::make_mut(&mut self.hashes);
+        let entries = Arc::make_mut(&mut self.entries);
+        for i in resident {
+            if self.resident_bytes <= target {
+                break;
+            }
+            entries[i].spilled = Some(spill.write(&hashes[i])?);
+            self.resident_bytes -= signature_bytes(&hashes[i]);
+            hashes[i] = Vec::new();
+        }
+        Ok(())
+    }
+
+    /// The bucketed prefix of a signature.
+    fn bucketed<'a>(&self, data_bytes: &'a [Vec<u8>]) -> &'a [Vec<u8>] {
+        &data_bytes[..self
+            .bucket_slots
+            .map_or(data_bytes.len(), |n| n.min(data_bytes.len()))]
+    }
+
+    /// Makes queries skip slots whose bucket holds more than `fraction` of
+    /// the indexed entries, like document-frequency pruning of shingles.
+    fn with_boilerplate_fraction(mut self, fraction: Option<f64>) -> PyResult<Self> {
+        if let Some(fraction) = fraction {
+            if !(fraction > 0.0 && fraction <= 1.0) {
+                return Err(PyValueError::new_err(
+                    "boilerplate_fraction must be in (0, 1]",
+                ));
+            }
+            if self.packed.is_some() {
+                return Err(PyValueError::new_err(
+                    "boilerplate_fraction requires full signatures and cannot be combined with bits",
+                ));
+            }
+        }
+        self.boilerplate_fraction = fraction;
+        Ok(self)
+    }
+
+    /// Whether the bucket of value `bytes` in `slot` is too common to mean
+    /// anything. Buckets below `BOILERPLATE_MIN_ENTRIES` never are, so small
+    /// indexes and exact duplicates are unaffected.
+    fn is_boilerplate(&self, slot: usize, bytes: &[u8]) -> bool {
+        let Some(fraction) = self.boilerplate_fraction else {
+            return false;
+        };
+        self.candidates
+            .get(&self.bucket_key(slot, bytes))
+            .is_some_and(|bucket| {
+                bucket.len() >= BOILERPLATE_MIN_ENTRIES
+                    && bucket.len() as f64 > fraction * self.ids.len() as f64
             })
-            .collect();
-        let mut result = FnvHashMap::default();
+    }
+
+    /// The bucket of value `bytes` in `slot`: its `key_bytes` most
+    /// significant bytes, which come last as slot bytes are little-endian,
+    /// read as an integer, or with `hashed_buckets` a `bucket_hasher` hash of
+    /// them and the slot index.
+    fn bucket_key(&self, slot: usize, bytes: &[u8]) -> u64 {
+        let bytes = &bytes[bytes.len() - self.key_bytes..];
+        if self.hashed_buckets {
+            return self.bucket_hasher.hash_slot(slot as u32, bytes);
+        }
+        bytes.iter().rev().fold(0, |key, &b| key << 8 | b as u64)
+    }
+
+    /// Bit-packed indexes only keep the packed codes, so anything that hands
+    /// out full signatures fails.
+    fn check_signatures_kept(&self) -> PyResult<()> {
+        match &self.packed {
+            Some(packed) => Err(PyValueError::new_err(format!(
+                "full signatures are not kept by an index with bits={}",
+                packed.bits()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Queries may use a prefix of the index width, inserts must match it
+    /// exactly. The first insert fixes the width.
+    fn check_width(&self, len: usize, insert: bool) -> PyResult<()> {
+        if len == 0 {
+            return Err(PyValueError::new_err("signature must not be empty"));
+        }
+        match self.width {
+            Some(width) if insert && len != width => Err(PyValueError::new_err(format!(
+                "cannot insert a signature of {} slots into an index of {} slots",
+                len, width
+            ))),
+            Some(width) if len > width => Err(PyValueError::new_err(format!(
+                "signature has {} slots but the index only stores {}",
+                len, width
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Signatures tagged with a hasher fingerprint must match the index's.
+    /// Untagged signatures are accepted since there is nothing to compare.
+    fn check_fingerprint(&self, fingerprint: Option<u64>) -> PyResult<()> {
+        match (self.fingerprint, fingerprint) {
+            (Some(expected), Some(got)) if expected != got => Err(PyValueError::new_err(format!(
+                "signature fingerprint {:#018x} does not match the index fingerprint {:#018x}; \
+                 it was produced by a hasher with a different configuration",
+                got, expected
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the index and similarity of every accepted entry whose
+    /// similarity is at least `threshold`, or the one `filter` gives its
+    /// language.
+    fn query(
+        &self,
+        data_bytes: &[Vec<u8>],
+        threshold: f64,
+        filter: &CandidateFilter,
+    ) -> PyResult<Vec<(usize, f64)>> {
+        self.check_width(data_bytes.len(), false)?;
+        filter.check_query_shingles()?;
+        let (candidates, skip) = self.candidates_of(data_bytes, filter.may_stop_early());
+        let packed_query = self.packed.as_ref().map(|p| p.pack(data_bytes));
+        let mut result = Vec::new();
         for i in candidates {
-            if let Some(hash) = self.hashes.get(i) {
-                let similarity = similarity_threshold(&data_bytes, hash);
-                if similarity >= threshold {
-                    if let Some(id) = self.ids.get(i) {
-                        result.insert(id.clone(), similarity);
-                    }
+            if filter.out_of_time() {
+                break;
+            }
+            if !filter.accepts(self, i)? {
+                continue;
+            }
+            let similarity = self.similarity(data_bytes, packed_query.as_deref(), &skip, i)?;
+            if similarity >= filter.threshold_for(self, i, threshold) {
+                if filter.max_results.is_some_and(|max| result.len() == max) {
+                    filter.overflow.set(true);
+                    break;
                 }
+                result.push((i, similarity));
             }
         }
-        result
+        filter.rescore(self, result)
     }
 
-    #[pyo3(
-        signature = (new_id, data, threshold = 0.5, add_if_dup = false),
-    )]
-    #[inline]
-    fn check_and_add(
+    /// The most similar entry, stopping early at an exact match.
+    fn best_match(&self, data_bytes: &[Vec<u8>]) -> PyResult<Option<(usize, f64)>> {
+        self.check_width(data_bytes.len(), false)?;
+        let (candidates, skip) = self.candidates_of(data_bytes, false);
+        let packed_query = self.packed.as_ref().map(|p| p.pack(data_bytes));
+        let mut best: Option<(usize, f64)> = None;
+        for i in candidates {
+            let similarity = self.similarity(data_bytes, packed_query.as_deref(), &skip, i)?;
+            if best.is_none_or(|(_, b)| similarity > b) {
+                best = Some((i, similarity));
+                if similarity >= 1.0 {
+                    break;
+                }
+            }
+        }
+        Ok(best)
+    }
+
+    /// Entries sharing a bucket with `data_bytes`, and which slots to skip
+    /// as boilerplate when `boilerplate_fraction` is set. With `ranked`, the
+    /// entries sharing the most buckets come first, which are the likeliest
+    /// matches when verification may stop early.
+    fn candidates_of(
+        &self,
+        data_bytes: &[Vec<u8>],
+        ranked: bool,
+    ) -> (Vec<usize>, Option<Vec<bool>>) {
+        let skip: Option<Vec<bool>> = self.boilerplate_fraction.map(|_| {
+            let slots = data_bytes.iter().enumerate();
+            slots
+                .map(|(slot, x)| self.is_boilerplate(slot, x))
+                .collect()
+        });
+        let buckets = self
+            .bucketed(data_bytes)
+            .iter()
+            .enumerate()
+            .filter(|&(slot, _)| !skip.as_ref().is_some_and(|skip| skip[slot]))
+            .filter_map(|(slot, x)| self.candidates.get(&self.bucket_key(slot, x)));
+        if !ranked {
+            let candidates: FnvHashSet<usize> = buckets.flatten().copied().collect();
+            return (candidates.into_iter().collect(), skip);
+        }
+        let mut collisions: FnvHashMap<usize, usize> = FnvHashMap::default();
+        for &i in buckets.flatten() {
+            *collisions.entry(i).or_default() += 1;
+        }
+        let mut candidates: Vec<(usize, usize)> = collisions.into_iter().collect();
+        candidates.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        (candidates.into_iter().map(|(i, _)| i).collect(), skip)
+    }
+
+    fn similarity(
+        &self,
+        data_bytes: &[Vec<u8>],
+        packed_query: Option<&[u64]>,
+        skip: &Option<Vec<bool>>,
+        i: usize,
+    ) -> PyResult<f64> {
+        if let (Some(packed), Some(query)) = (&self.packed, packed_query) {
+            return Ok(packed.similarity(query, data_bytes.len(), i));
+        }
+        let hashes = self.hashes_of(i)?;
+        Ok(match skip {
+            Some(skip) => similarity_skipping(data_bytes, &hashes, skip),
+            None => similarity_threshold(data_bytes, &hashes),
+        })
+    }
+
+    /// Unfiltered query by signature, for the composite indexes.
+    pub(crate) fn similar(
+        &self,
+        data: &[f32],
+        threshold: f64,
+    ) -> PyResult<FnvHashMap<String, f64>> {
+        let matches = self.query(&to_bytes(data), threshold, &CandidateFilter::default())?;
+        Ok(self.to_id_map(&matches))
+    }
+
+    pub(crate) fn add_signature(&mut self, new_id: &str, data: Vec<f32>) -> PyResult<usize> {
+        self.insert(new_id, to_bytes(&data), EntryInfo::new(None, None))
+    }
+
+    /// Number of bucketed slots of `data` whose bucket holds some entry.
+    pub(crate) fn bucket_collisions(&self, data: &[f32]) -> usize {
+        let data_bytes = to_bytes(data);
+        self.bucketed(&data_bytes)
+            .iter()
+            .enumerate()
+            .filter(|&(slot, bytes)| self.candidates.contains_key(&self.bucket_key(slot, bytes)))
+            .count()
+    }
+
+    /// Inserts unconditionally and joins the new entry to the cluster of
+    /// everything it matches.
+    pub(crate) fn add_linked(
         &mut self,
         new_id: &str,
         data: Vec<f32>,
         threshold: f64,
-        add_if_dup: bool,
-    ) -> FnvHashMap<String, f64> {
-        let data_bytes: Vec<Vec<u8>> = data
+    ) -> PyResult<usize> {
+        let data_bytes = to_bytes(&data);
+        let matches = self.query(&data_bytes, threshold, &CandidateFilter::default())?;
+        let index = self.insert(new_id, data_bytes, EntryInfo::new(None, None))?;
+        for (i, _) in matches {
+            Arc::make_mut(&mut self.clusters).union(index, i);
+        }
+        Ok(index)
+    }
+
+    pub(crate) fn cluster_label(&self, index: usize) -> usize {
+        self.clusters.find(index)
+    }
+
+    fn signature(&self, i: usize) -> PyResult<Vec<f32>> {
+        Ok(from_bytes(&self.hashes_of(i)?))
+    }
+
+    /// The `k` most similar entries reaching `threshold`, best first and
+    /// older first among equals.
+    fn top_k_of(
+        &self,
+        data_bytes: &[Vec<u8>],
+        k: usize,
+        threshold: f64,
+    ) -> PyResult<Vec<(String, f64)>> {
+        let mut matches = self.query(data_bytes, threshold, &CandidateFilter::default())?;
+        matches.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        matches.truncate(k);
+        Ok(matches
+            .into_iter()
+            .map(|(i, similarity)| (self.ids[i].clone(), similarity))
+            .collect())
+    }
+
+    /// Another handle on the entry data as it is now, for `reader`; only
+    /// the `Arc`s are cloned. The mutation log, audit log and rejected
+    /// inserts are left out.
+    fn snapshot(&self) -> LSH {
+        LSH {
+            candidates: Arc::clone(&self.candidates),
+            key_bytes: self.key_bytes,
+            hashed_buckets: self.hashed_buckets,
+            bucket_hasher: self.bucket_hasher,
+            boilerplate_fraction: self.boilerplate_fraction,
+            bucket_slots: self.bucket_slots,
+            hashes: Arc::clone(&self.hashes),
+            ids: Arc::clone(&self.ids),
+            id_map: Arc::clone(&self.id_map),
+            entries: Arc::clone(&self.entries),
+            next_auto_id: self.next_auto_id,
+            next_seq: self.next_seq,
+            clusters: Arc::clone(&self.clusters),
+            pending_links: Arc::clone(&self.pending_links),
+            width: self.width,
+            fingerprint: self.fingerprint,
+            log: None,
+            audit: None,
+            rejected: Vec::new(),
+            packed: self.packed.clone(),
+            max_memory_bytes: self.max_memory_bytes,
+            resident_bytes: self.resident_bytes,
+            spill: self.spill.clone(),
+        }
+    }
+
+    fn to_id_map(&self, matches: &[(usize, f64)]) -> FnvHashMap<String, f64> {
+        matches
             .iter()
-            .map(|x| {
-                let mut bytes = [0u8; 4];
-                bytes.copy_from_slice(&x.to_le_bytes());
-                bytes.to_vec()
-            })
-            .collect();
-        let result = self.check(data, threshold);
-        if result.is_empty() || add_if_dup {
-            let len_id = self.ids.len();
-            self.id_map.insert(new_id.to_string(), len_id);
-            self.ids.push(new_id.to_string());
-            for i in 0..data_bytes.len() {
-                self.candidates
-                    .entry(data_bytes[i].clone())
-                    .or_insert(FnvHashSet::default())
-                    .insert(len_id);
+            .map(|&(i, similarity)| (self.ids[i].clone(), similarity))
+            .collect()
+    }
+
+    /// `{id: weight}` of the matched entries.
+    fn weights_of(&self, matches: &[(usize, f64)]) -> FnvHashMap<String, f64> {
+        matches
+            .iter()
+            .map(|&(i, _)| (self.ids[i].clone(), self.entries[i].weight))
+            .collect()
+    }
+
+    /// Queries and, when nothing matched or `add_if_dup` is set, inserts the
+    /// signature and links it to the cluster of its matches. Also returns
+    /// the new entry's index if it was inserted. A query that ran out of
+    /// its time budget may have missed a match, so without `add_if_dup`
+    /// nothing is inserted then.
+    #[allow(clippy::type_complexity)]
+    fn check_and_insert(
+        &mut self,
+        new_id: &str,
+        data_bytes: Vec<Vec<u8>>,
+        threshold: f64,
+        add_if_dup: bool,
+        filter: &CandidateFilter,
+        mut info: EntryInfo,
+    ) -> PyResult<(Vec<(usize, f64)>, Option<usize>)> {
+        let matches = self.query(&data_bytes, threshold, filter)?;
+        for &(i, _) in &matches {
+            let entry = &mut Arc::make_mut(&mut self.entries)[i];
+            entry.matched += 1;
+            entry.last_used = self.next_seq;
+        }
+        if !matches.is_empty() && !add_if_dup {
+            if let Some(audit) = &mut self.audit {
+                let matched = matches
+                    .iter()
+                    .map(|&(i, similarity)| (self.ids[i].clone(), similarity))
+                    .collect();
+                audit.push(new_id, info.timestamp, matched);
+            }
+            let best = matches
+                .iter()
+                .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)));
+            if let Some(&(i, _)) = best {
+                Arc::make_mut(&mut self.entries)[i].absorbed += info.weight;
+            }
+            self.rejected.push((new_id.to_string(), info.metadata));
+            return Ok((matches, None));
+        }
+        if filter.truncated.get() && !add_if_dup {
+            return Ok((matches, None));
+        }
+        info.duplicate = !matches.is_empty();
+        let index = self.insert(new_id, data_bytes, info)?;
+        for &(i, _) in &matches {
+            Arc::make_mut(&mut self.clusters).union(index, i);
+        }
+        Ok((matches, Some(index)))
+    }
+
+    fn insert(
+        &mut self,
+        new_id: &str,
+        data_bytes: Vec<Vec<u8>>,
+        mut info: EntryInfo,
+    ) -> PyResult<usize> {
+        self.check_width(data_bytes.len(), true)?;
+        if info.shingles.is_some() {
+            info.distinct = Some(Signature::F32(from_bytes(&data_bytes)).estimated_cardinality());
+        }
+        info.seq = self.next_seq;
+        self.next_seq += 1;
+        self.width = Some(data_bytes.len());
+        let len_id = self.ids.len();
+        Arc::make_mut(&mut self.id_map).insert(new_id.to_string(), len_id);
+        Arc::make_mut(&mut self.ids).push(new_id.to_string());
+        for (slot, bytes) in self.bucketed(&data_bytes).iter().enumerate() {
+            let key = self.bucket_key(slot, bytes);
+            Arc::make_mut(&mut self.candidates)
+                .entry(key)
+                .or_default()
+                .insert(len_id);
+        }
+        match &mut self.packed {
+            Some(packed) => {
+                let codes = packed.pack(&data_bytes);
+                Arc::make_mut(packed).push(codes);
+                Arc::make_mut(&mut self.hashes).push(Vec::new());
             }
-            self.hashes.push(data_bytes);
+            None => {
+                self.resident_bytes += signature_bytes(&data_bytes);
+                Arc::make_mut(&mut self.hashes).push(data_bytes);
+            }
+        }
+        info.last_used = info.seq;
+        Arc::make_mut(&mut self.entries).push(info);
+        Arc::make_mut(&mut self.clusters).push();
+        self.apply_pending_links(new_id, len_id);
+        let entry = self
+            .log
+            .is_some()
+            .then(|| self.delta_entry(len_id))
+            .transpose()?;
+        if let (Some(log), Some(entry)) = (&mut self.log, entry) {
+            log.push(Record::Add(entry));
         }
-        result
+        self.enforce_memory_budget()?;
+        Ok(len_id)
     }
+
+    /// Snapshot for `to_dict`. Entries are stored in the delta encoding and
+    /// buckets are left out, `from_state` rebuilds them from the signatures.
+    /// A bit-packed index has no full signatures to rebuild from, so its
+    /// entries carry empty ones and the packed codes, buckets and distinct
+    /// shingle estimates are stored next to them.
+    fn state<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let delta = Delta {
+            fingerprint: self.fingerprint,
+            records: (0..self.ids.len())
+                .map(|i| Ok(Record::Add(self.delta_entry(i)?)))
+                .collect::<PyResult<_>>()?,
+        };
+        let clusters: Vec<usize> = (0..self.ids.len()).map(|i| self.clusters.find(i)).collect();
+        let state = PyDict::new(py);
+        state.set_item("format", STATE_FORMAT)?;
+        state.set_item("fingerprint", self.fingerprint)?;
+        state.set_item("key_bytes", self.key_bytes)?;
+        state.set_item("hashed_buckets", self.hashed_buckets)?;
+        state.set_item("bucket_hasher", self.bucket_hasher.name())?;
+        state.set_item("boilerplate_fraction", self.boilerplate_fraction)?;
+        state.set_item("bucket_slots", self.bucket_slots)?;
+        state.set_item("max_memory_bytes", self.max_memory_bytes)?;
+        state.set_item("next_auto_id", self.next_auto_id)?;
+        state.set_item("next_seq", self.next_seq)?;
+        state.set_item("entries", PyBytes::new(py, &crate::delta::encode(&delta)))?;
+        state.set_item("clusters", clusters)?;
+        state.set_item("pending_links", &*self.pending_links)?;
+        state.set_item("bits", self.packed.as_ref().map(|p| p.bits()))?;
+        if let Some(packed) = &self.packed {
+            let distinct: Vec<Option<f64>> = self.entries.iter().map(|e| e.distinct).collect();
+            state.set_item("width", self.width)?;
+            state.set_item("codes", packed.codes())?;
+            state.set_item("buckets", &*self.candidates)?;
+            state.set_item("distinct", distinct)?;
+        }
+        Ok(state)
+    }
+
+    fn from_state(state: &Bound<'_, PyDict>) -> PyResult<Self> {
+        let format: u32 = required(state, "format")?;
+        if format != STATE_FORMAT {
+            return Err(PyValueError::new_err(format!(
+                "unsupported state format {}",
+                format
+            )));
+        }
+        let mut lsh = LSH::new(required(state, "fingerprint")?)
+            .with_bits(required(state, "bits")?)?
+            .with_key_bytes(required(state, "key_bytes")?)?
+            .with_hashed_buckets(required(state, "hashed_buckets")?)
+            .with_bucket_hasher(ShingleHasher::parse(&required::<String>(
+                state,
+                "bucket_hasher",
+            )?)?)
+            .with_boilerplate_fraction(required(state, "boilerplate_fraction")?)?
+            .with_bucket_slots(required(state, "bucket_slots")?)?
+            .with_max_memory_bytes(required(state, "max_memory_bytes")?)?;
+        let entries: Bound<PyBytes> = required(state, "entries")?;
+        let delta = crate::delta::decode(entries.as_bytes())?;
+        lsh.check_fingerprint(delta.fingerprint)?;
+        let mut packed = None;
+        if lsh.packed.is_some() {
+            let codes: Vec<Vec<u64>> = required(state, "codes")?;
+            let distinct: Vec<Option<f64>> = required(state, "distinct")?;
+            if codes.len() != delta.records.len() || distinct.len() != delta.records.len() {
+                return Err(PyValueError::new_err(
+                    "state packed codes do not match its entries",
+                ));
+            }
+            packed = Some(codes.into_iter().zip(distinct));
+        }
+        for record in delta.records {
+            let Record::Add(entry) = record else {
+                return Err(PyValueError::new_err("state entries contain a removal"));
+            };
+            let mut info = EntryInfo::new(Some(entry.metadata), Some(entry.timestamp));
+            info.shingles = entry.shingles;
+            info.text = entry.text;
+            info.weight = entry.weight;
+            info.language = entry.language;
+            let index = match packed.as_mut().and_then(|p| p.next()) {
+                Some((codes, distinct)) => {
+                    info.distinct = distinct;
+                    lsh.restore_packed(&entry.id, codes, info)
+                }
+                None => lsh.insert(&entry.id, to_bytes(&entry.signature), info)?,
+            };
+            Arc::make_mut(&mut lsh.entries)[index].seq = entry.seq;
+        }
+        if let Some(packed) = &lsh.packed {
+            let n = lsh.ids.len();
+            let width: Option<usize> = required(state, "width")?;
+            let buckets: FnvHashMap<u64, FnvHashSet<usize>> = required(state, "buckets")?;
+            if width.is_some_and(|width| width == 0 || !packed.fits(width))
+                || (width.is_none() && n > 0)
+                || buckets.values().flatten().any(|&i| i >= n)
+            {
+                return Err(PyValueError::new_err(
+                    "state packed codes do not match its entries",
+                ));
+            }
+            lsh.width = width;
+            lsh.candidates = Arc::new(buckets);
+        }
+        lsh.next_seq = required(state, "next_seq")?;
+        lsh.next_auto_id = required(state, "next_auto_id")?;
+        let clusters: Vec<usize> = required(state, "clusters")?;
+        if clusters.len() != lsh.ids.len() || clusters.iter().any(|&root| root >= clusters.len()) {
+            return Err(PyValueError::new_err(
+                "state clusters do not match its entries",
+            ));
+        }
+        for (i, root) in clusters.into_iter().enumerate() {
+            Arc::make_mut(&mut lsh.clusters).union(i, root);
+        }
+        lsh.pending_links = Arc::new(required(state, "pending_links")?);
+        Ok(lsh)
+    }
+
+    /// Adds an entry of a bit-packed `from_state` from its packed `codes`;
+    /// the caller restores the buckets.
+    fn restore_packed(&mut self, new_id: &str, codes: Vec<u64>, mut info: EntryInfo) -> usize {
+        let len_id = self.ids.len();
+        info.seq = self.next_seq;
+        self.next_seq += 1;
+        info.last_used = info.seq;
+        Arc::make_mut(&mut self.id_map).insert(new_id.to_string(), len_id);
+        Arc::make_mut(&mut self.ids).push(new_id.to_string());
+        if let Some(packed) = &mut self.packed {
+            Arc::make_mut(packed).push(codes);
+        }
+        Arc::make_mut(&mut self.hashes).push(Vec::new());
+        Arc::make_mut(&mut self.entries).push(info);
+        Arc::make_mut(&mut self.clusters).push();
+        len_id
+    }
+
+    fn delta_entry(&self, i: usize) -> PyResult<DeltaEntry> {
+        let info = &self.entries[i];
+        Ok(DeltaEntry {
+            seq: info.seq,
+            id: self.ids[i].clone(),
+            timestamp: info.timestamp,
+            weight: info.weight,
+            language: info.language.clone(),
+            shingles: info.shingles,
+            metadata: info.metadata.clone(),
+            text: info.text.clone(),
+            signature: self.signature(i)?,
+        })
+    }
+
+    /// Removes every entry `i` with `removed[i]` in one pass and returns the
+    /// new index of each old entry, `None` for the removed ones. Clusters
+    /// keep the links their remaining members had through removed entries.
+    fn remove_entries(&mut self, removed: &[bool]) -> Vec<Option<usize>> {
+        let mut next = 0;
+        let remap: Vec<Option<usize>> = removed
+            .iter()
+            .map(|&r| {
+                (!r).then(|| {
+                    next += 1;
+                    next - 1
+                })
+            })
+            .collect();
+        if let Some(log) = &mut self.log {
+            for (i, _) in removed.iter().enumerate().filter(|(_, &r)| r) {
+                log.push(Record::Remove(self.ids[i].clone()));
+            }
+        }
+        Arc::make_mut(&mut self.candidates).retain(|_, members| {
+            *members = members.iter().filter_map(|&i| remap[i]).collect();
+            !members.is_empty()
+        });
+        // The first remaining member of each old cluster anchors it.
+        let mut anchors: FnvHashMap<usize, usize> = FnvHashMap::default();
+        let mut clusters = UnionFind::default();
+        for (i, new) in remap.iter().enumerate() {
+            if let Some(new) = *new {
+                clusters.push();
+                let anchor = *anchors.entry(self.clusters.find(i)).or_insert(new);
+                clusters.union(anchor, new);
+            }
+        }
+        self.clusters = Arc::new(clusters);
+        retain_unflagged(Arc::make_mut(&mut self.ids), removed);
+        retain_unflagged(Arc::make_mut(&mut self.hashes), removed);
+        retain_unflagged(Arc::make_mut(&mut self.entries), removed);
+        self.resident_bytes = self.hashes.iter().map(|h| signature_bytes(h)).sum();
+        if let Some(packed) = &mut self.packed {
+            Arc::make_mut(packed).retain(removed);
+        }
+        self.id_map = Arc::new(
+            self.ids
+                .iter()
+                .enumerate()
+                .map(|(i, id)| (id.clone(), i))
+                .collect(),
+        );
+        remap
+    }
+
+    /// Descriptions of every broken internal invariant found, see
+    /// `verify_integrity`.
+    fn integrity_problems(&self) -> Vec<String> {
+        let n = self.ids.len();
+        let mut problems = Vec::new();
+        for (name, len) in [
+            ("signatures", self.hashes.len()),
+            ("entry records", self.entries.len()),
+            ("cluster entries", self.clusters.len()),
+        ] {
+            if len != n {
+                problems.push(format!("{} {} for {} ids", len, name, n));
+            }
+        }
+        if let Some(packed) = &self.packed {
+            if packed.len() != n {
+                problems.push(format!("{} packed signatures for {} ids", packed.len(), n));
+            }
+        }
+        if !problems.is_empty() {
+            return problems;
+        }
+        if self.id_map.len() != n {
+            problems.push(format!(
+                "id map holds {} ids, the index {}",
+                self.id_map.len(),
+                n
+            ));
+        }
+        for (i, id) in self.ids.iter().enumerate() {
+            if self.id_map.get(id) != Some(&i) {
+                problems.push(format!("id map does not point {:?} at entry {}", id, i));
+            }
+        }
+        match (self.width, &self.packed) {
+            (None, _) if n > 0 => problems.push("entries but no signature width".to_string()),
+            (Some(width), Some(packed)) if !packed.fits(width) => {
+                problems.push(format!("packed signatures not all {} slots wide", width));
+            }
+            (Some(width), None) => {
+                for i in 0..n {
+                    let Ok(data_bytes) = self.hashes_of(i) else {
+                        problems.push(format!("entry {} cannot be read back", i));
+                        continue;
+                    };
+                    if data_bytes.len() != width {
+                        problems.push(format!(
+                            "entry {} has {} slots, the index {}",
+                            i,
+                            data_bytes.len(),
+                            width
+                        ));
+                    }
+                }
+            }
+            _ => {}
+        }
+        for (&key, members) in self.candidates.iter() {
+            if !self.hashed_buckets && key >> (8 * self.key_bytes) != 0 {
+                problems.push(format!(
+                    "bucket key {:#x} is wider than {} bytes",
+                    key, self.key_bytes
+                ));
+            }
+            if members.is_empty() {
+                problems.push("empty bucket".to_string());
+            }
+            for &i in members {
+                if i >= n {
+                    problems.push(format!("bucket member {} does not exist", i));
+                }
+            }
+        }
+        if self.packed.is_none() {
+            for i in 0..n {
+                let Ok(data_bytes) = self.hashes_of(i) else {
+                    continue;
+                };
+                let mut slots = self.bucketed(&data_bytes).iter().enumerate();
+                let missing = slots.any(|(slot, bytes)| {
+                    !self
+                        .candidates
+                        .get(&self.bucket_key(slot, bytes))
+                        .is_some_and(|members| members.contains(&i))
+                });
+                if missing {
+                    problems.push(format!("entry {} is missing from its buckets", i));
+                }
+            }
+        }
+        let mut seqs = FnvHashSet::default();
+        for (i, info) in self.entries.iter().enumerate() {
+            if info.seq >= self.next_seq || !seqs.insert(info.seq) {
+                problems.push(format!(
+                    "entry {} has a reused sequence number {}",
+                    i, info.seq
+                ));
+            }
+        }
+        problems.extend(self.clusters.problems());
+        for (id, others) in self.pending_links.iter() {
+            if !self.id_map.contains_key(id) {
+                continue;
+            }
+            for other in others
+                .iter()
+                .filter(|other| self.id_map.contains_key(*other))
+            {
+                problems.push(format!(
+                    "pending link between {:?} and {:?} was never applied",
+                    id, other
+                ));
+            }
+        }
+        problems
+    }
+
+    fn fragmentation_of(&self) -> Fragmentation {
+        let mut wasted = table_excess(self.candidates.capacity(), self.candidates.len());
+        let mut bytes = table_slack::<(u64, FnvHashSet<usize>)>(
+            self.candidates.capacity(),
+            self.candidates.len(),
+        );
+        for members in self.candidates.values() {
+            wasted += table_excess(members.capacity(), members.len());
+            bytes += table_slack::<usize>(members.capacity(), members.len());
+        }
+        bytes += vec_slack(&self.ids)
+            + vec_slack(&self.hashes)
+            + vec_slack(&self.entries)
+            + table_slack::<(String, usize)>(self.id_map.capacity(), self.id_map.len());
+        if let Some(packed) = &self.packed {
+            bytes += packed.slack();
+        }
+        Fragmentation {
+            dead_slots: self.entries.capacity() - self.entries.len(),
+            wasted_bucket_entries: wasted,
+            recoverable_bytes: bytes,
+        }
+    }
+
+    fn shrink(&mut self) {
+        Arc::make_mut(&mut self.candidates).shrink_to_fit();
+        for members in Arc::make_mut(&mut self.candidates).values_mut() {
+            members.shrink_to_fit();
+        }
+        Arc::make_mut(&mut self.ids).shrink_to_fit();
+        Arc::make_mut(&mut self.hashes).shrink_to_fit();
+        Arc::make_mut(&mut self.entries).shrink_to_fit();
+        Arc::make_mut(&mut self.id_map).shrink_to_fit();
+        if let Some(packed) = &mut self.packed {
+            Arc::make_mut(packed).shrink_to_fit();
+        }
+    }
+
+    /// `apply_delta`, calling `remapped` with the result of every
+    /// `remove_entries` it does. Consecutive removals share one pass.
+    fn apply_delta_with(
+        &mut self,
+        data: &[u8],
+        mut remapped: impl FnMut(&[Option<usize>]),
+    ) -> PyResult<usize> {
+        let delta = crate::delta::decode(data)?;
+        self.check_fingerprint(delta.fingerprint)?;
+        let width = delta.records.iter().find_map(|record| match record {
+            Record::Add(entry) => Some(entry.signature.len()),
+            Record::Remove(_) => None,
+        });
+        if let Some(width) = width {
+            self.check_width(width, true)?;
+        }
+        let mut applied = 0;
+        let mut removed: Option<Vec<bool>> = None;
+        for record in delta.records {
+            match record {
+                Record::Add(entry) => {
+                    if let Some(removed) = removed.take() {
+                        remapped(&self.remove_entries(&removed));
+                    }
+                    if self.id_map.contains_key(&entry.id) {
+                        continue;
+                    }
+                    let mut info = EntryInfo::new(Some(entry.metadata), Some(entry.timestamp));
+                    info.shingles = entry.shingles;
+                    info.text = entry.text;
+                    info.weight = entry.weight;
+                    info.language = entry.language;
+                    self.insert(&entry.id, to_bytes(&entry.signature), info)?;
+                }
+                Record::Remove(id) => {
+                    let Some(&i) = self.id_map.get(&id) else {
+                        continue;
+                    };
+                    let removed = removed.get_or_insert_with(|| vec![false; self.ids.len()]);
+                    if removed[i] {
+                        continue;
+                    }
+                    removed[i] = true;
+                }
+            }
+            applied += 1;
+        }
+        if let Some(removed) = removed {
+            remapped(&self.remove_entries(&removed));
+        }
+        if applied > 0 {
+            self.fingerprint = self.fingerprint.or(delta.fingerprint);
+        }
+        Ok(applied)
+    }
+
+    /// Flags the entries `remove_if` removes: those whose metadata matches
+    /// `filter`, a dict of required values or a predicate on the metadata.
+    fn flag_matching(&self, filter: &Bound<'_, PyAny>) -> PyResult<Vec<bool>> {
+        let filter = MetadataFilter::from_py(filter)?;
+        self.entries
+            .iter()
+            .map(|entry| filter.matches(&entry.metadata))
+            .collect()
+    }
+
+    /// Flags all but the `per_cluster` newest or oldest members of every
+    /// duplicate cluster, by timestamp and then insertion order.
+    fn flag_pruned(&self, keep: &str, per_cluster: usize) -> PyResult<Vec<bool>> {
+        let newest = match keep {
+            "newest" => true,
+            "oldest" => false,
+            _ => return Err(PyValueError::new_err("keep must be 'newest' or 'oldest'")),
+        };
+        if per_cluster == 0 {
+            return Err(PyValueError::new_err("per_cluster must be greater than 0"));
+        }
+        let mut removed = vec![false; self.ids.len()];
+        for root in (0..self.ids.len()).filter(|&i| self.clusters.find(i) == i) {
+            let mut members = self.clusters.members(root).to_vec();
+            if members.len() <= per_cluster {
+                continue;
+            }
+            members.sort_by(|&a, &b| {
+                let (a, b) = (&self.entries[a], &self.entries[b]);
+                a.timestamp.total_cmp(&b.timestamp).then(a.seq.cmp(&b.seq))
+            });
+            if newest {
+                members.reverse();
+            }
+            for &i in &members[per_cluster..] {
+                removed[i] = true;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Flags the indexed ones among `ids`.
+    fn flag_ids(&self, ids: &[String]) -> Vec<bool> {
+        let mut removed = vec![false; self.ids.len()];
+        for id in ids {
+            if let Some(&i) = self.id_map.get(id) {
+                removed[i] = true;
+            }
+        }
+        removed
+    }
+
+    fn apply_pending_links(&mut self, new_id: &str, index: usize) {
+        let Some(others) = Arc::make_mut(&mut self.pending_links).remove(new_id) else {
+            return;
+        };
+        let mut waiting = Vec::new();
+        for other in others {
+            match self.id_map.get(&other) {
+                Some(&j) => {
+                    Arc::make_mut(&mut self.clusters).union(index, j);
+                    let links = Arc::make_mut(&mut self.pending_links);
+                    if let Some(back) = links.get_mut(&other) {
+                        back.retain(|id| id != new_id);
+                        if back.is_empty() {
+                            links.remove(&other);
+                        }
+                    }
+                }
+                None => waiting.push(other),
+            }
+        }
+        if !waiting.is_empty() {
+            Arc::make_mut(&mut self.pending_links).insert(new_id.to_string(), waiting);
+        }
+    }
+
+    /// The rejection log, or an error when audit mode is off.
+    fn audit_log(&self) -> PyResult<&AuditLog> {
+        self.audit.as_ref().ok_or_else(|| {
+            PyValueError::new_err("audit mode is not enabled; call enable_audit() first")
+        })
+    }
+
+    /// Returns the next unused auto-generated id. Ids supplied by the caller
+    /// are skipped, so auto ids never collide with existing entries.
+    fn next_id(&mut self) -> String {
+        loop {
+            let id = self.next_auto_id.to_string();
+            self.next_auto_id += 1;
+            if !self.id_map.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+}
+
+#[pymethods]
+impl LSH {
+    /// With `bits`, only that many bits of each slot are kept and candidates
+    /// are verified by Hamming distance over the packed codes. `key_bytes`
+    /// sets how many of each slot's 4 bytes the buckets are keyed on. With
+    /// `boilerplate_fraction`, queries ignore slots whose bucket holds more
+    /// than that fraction of all entries. With `bucket_slots`, only the first
+    /// that many slots are bucketed and the rest only serve verification.
+    /// With `hashed_buckets`, bucket keys hash in the slot index, so values
+    /// only collide within a slot. With `max_memory_bytes`, signatures past
+    /// that many bytes spill to a temp file, coldest first, and are read
+    /// back from it when verified; buckets and ids stay in memory.
+    #[new]
+    #[pyo3(signature = (
+        fingerprint = None, bits = None, key_bytes = 4, boilerplate_fraction = None,
+        bucket_slots = None, hashed_buckets = false, max_memory_bytes = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn py_new(
+        fingerprint: Option<u64>,
+        bits: Option<u32>,
+        key_bytes: usize,
+        boilerplate_fraction: Option<f64>,
+        bucket_slots: Option<usize>,
+        hashed_buckets: bool,
+        max_memory_bytes: Option<usize>,
+    ) -> PyResult<Self> {
+        LSH::new(fingerprint)
+            .with_bits(bits)?
+            .with_key_bytes(key_bytes)?
+            .with_hashed_buckets(hashed_buckets)
+            .with_boilerplate_fraction(boilerplate_fraction)?
+            .with_bucket_slots(bucket_slots)?
+            .with_max_memory_bytes(max_memory_bytes)
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.ids.to_vec()
+    }
+
+    fn values(&self) -> PyResult<Vec<Vec<f32>>> {
+        self.check_signatures_kept()?;
+        (0..self.hashes.len()).map(|i| self.signature(i)).collect()
+    }
+
+    fn bits(&self) -> Option<u32> {
+        self.packed.as_ref().map(|p| p.bits())
+    }
+
+    fn sequence_of(&self, id: &str) -> Option<u64> {
+        self.id_map.get(id).map(|&i| self.entries[i].seq)
+    }
+
+    /// Sequence number the next inserted entry will receive, usable as the
+    /// cursor for the next `export_since` call.
+    fn next_sequence(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// Serializes every entry with a sequence number of at least
+    /// `sequence_no` (see `delta::encode` for the layout).
+    #[pyo3(signature = (sequence_no = 0))]
+    fn export_since<'py>(
+        &self,
+        py: Python<'py>,
+        sequence_no: u64,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        self.check_signatures_kept()?;
+        let mut order: Vec<usize> = (0..self.ids.len())
+            .filter(|&i| self.entries[i].seq >= sequence_no)
+            .collect();
+        order.sort_by_key(|&i| self.entries[i].seq);
+        let records = order
+            .into_iter()
+            .map(|i| Ok(Record::Add(self.delta_entry(i)?)))
+            .collect::<PyResult<_>>()?;
+        let delta = Delta {
+            fingerprint: self.fingerprint,
+            records,
+        };
+        Ok(PyBytes::new(py, &crate::delta::encode(&delta)))
+    }
+
+    /// Applies the records of a delta produced by `export_since` or
+    /// `drain_log`, without duplicate checks. Additions whose id is already
+    /// indexed and removals of ids that are not are skipped, so applying the
+    /// same delta twice is harmless. Returns how many records changed the
+    /// index.
+    fn apply_delta(&mut self, data: &[u8]) -> PyResult<usize> {
+        self.apply_delta_with(data, |_| {})
+    }
+
+    /// Removes the indexed ones among `ids` with a single rebuild of the
+    /// buckets, and returns how many that was. Unknown ids are ignored.
+    fn remove_many(&mut self, ids: Vec<String>) -> usize {
+        let removed = self.flag_ids(&ids);
+        let count = removed.iter().filter(|&&r| r).count();
+        if count > 0 {
+            self.remove_entries(&removed);
+        }
+        count
+    }
+
+    /// Removes all but `per_cluster` entries of every duplicate cluster,
+    /// keeping the `"newest"` or `"oldest"` by timestamp, which turns a
+    /// dedup index into one of canonical documents. Returns how many were
+    /// removed.
+    #[pyo3(signature = (keep = "newest", per_cluster = 1))]
+    fn prune(&mut self, keep: &str, per_cluster: usize) -> PyResult<usize> {
+        let removed = self.flag_pruned(keep, per_cluster)?;
+        let count = removed.iter().filter(|&&r| r).count();
+        if count > 0 {
+            self.remove_entries(&removed);
+        }
+        Ok(count)
+    }
+
+    /// Room the index holds beyond its entries: `dead_slots` entry slots
+    /// left by removals and growth, `wasted_bucket_entries` unused bucket
+    /// capacity, and `recoverable_bytes`, roughly what `compact()` frees.
+    /// Removals already renumber entries, so compacting only reallocates.
+    fn fragmentation(&self) -> FnvHashMap<String, usize> {
+        self.fragmentation_of().into_dict()
+    }
+
+    /// Releases the room reported by `fragmentation()` and returns the
+    /// estimated number of bytes freed.
+    fn compact(&mut self) -> usize {
+        let freed = self.fragmentation_of().recoverable_bytes;
+        self.shrink();
+        freed
+    }
+
+    /// Checks the internal invariants of the index: ids, signatures and
+    /// entry records line up, every bucket member exists and every entry is
+    /// in the buckets of its slots, signatures share one width, clusters
+    /// are consistent and no known link between two indexed ids is left
+    /// pending. Returns a description of each problem found; an empty list
+    /// means the index is sound.
+    fn verify_integrity(&self) -> Vec<String> {
+        self.integrity_problems()
+    }
+
+    /// One id per duplicate cluster, unique entries included, in insertion
+    /// order: the ids `prune(keep)` would leave in the index.
+    #[pyo3(signature = (keep = "newest"))]
+    fn canonical_ids(&self, keep: &str) -> PyResult<Vec<String>> {
+        let removed = self.flag_pruned(keep, 1)?;
+        let mut kept: Vec<usize> = (0..self.ids.len()).filter(|&i| !removed[i]).collect();
+        kept.sort_by_key(|&i| self.entries[i].seq);
+        Ok(kept.into_iter().map(|i| self.ids[i].clone()).collect())
+    }
+
+    /// Removes every entry whose metadata matches `filter`, a dict of
+    /// required values or a callable taking the metadata dict, like the
+    /// `filter` of `check`. Returns how many were removed.
+    fn remove_if(&mut self, filter: &Bound<'_, PyAny>) -> PyResult<usize> {
+        let removed = self.flag_matching(filter)?;
+        let count = removed.iter().filter(|&&r| r).count();
+        if count > 0 {
+            self.remove_entries(&removed);
+        }
+        Ok(count)
+    }
+
+    /// Starts recording every mutation of the index until `disable_log` is
+    /// called. Recorded mutations are consumed with `drain_log`.
+    fn enable_log(&mut self) -> PyResult<()> {
+        self.check_signatures_kept()?;
+        self.log.get_or_insert_with(Vec::new);
+        Ok(())
+    }
+
+    /// Stops recording mutations and discards any that were not drained.
+    fn disable_log(&mut self) {
+        self.log = None;
+    }
+
+    /// Returns the mutations recorded since the previous call, oldest first,
+    /// in the format accepted by `apply_delta`, and clears them.
+    fn drain_log<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        let Some(log) = self.log.as_mut() else {
+            return Err(PyValueError::new_err(
+                "mutation log is not enabled; call enable_log() first",
+            ));
+        };
+        let delta = Delta {
+            fingerprint: self.fingerprint,
+            records: std::mem::take(log),
+        };
+        Ok(PyBytes::new(py, &crate::delta::encode(&delta)))
+    }
+
+    /// Starts recording, for every insert rejected as a duplicate, the ids
+    /// it matched and their similarities.
+    fn enable_audit(&mut self) {
+        self.audit.get_or_insert_with(AuditLog::default);
+    }
+
+    /// Stops audit mode and discards what was recorded.
+    fn disable_audit(&mut self) {
+        self.audit = None;
+    }
+
+    /// `(timestamp, [(matched id, similarity), ...])` for each rejected
+    /// insert of `id`, oldest first, best match first.
+    #[allow(clippy::type_complexity)]
+    fn audit(&self, id: &str) -> PyResult<Vec<(f64, Vec<(String, f64)>)>> {
+        Ok(self
+            .audit_log()?
+            .get(id)
+            .map(|record| (record.timestamp, record.matches.clone()))
+            .collect())
+    }
+
+    /// Every recorded rejection as JSON Lines, one object per line with the
+    /// `id`, `timestamp` and `matches` as `{"id", "similarity"}` objects.
+    fn export_audit(&self) -> PyResult<String> {
+        Ok(self.audit_log()?.to_jsonl())
+    }
+
+    /// Number of rejected inserts recorded since audit mode was enabled or
+    /// last cleared.
+    fn audit_count(&self) -> PyResult<usize> {
+        Ok(self.audit_log()?.len())
+    }
+
+    fn clear_audit(&mut self) -> PyResult<()> {
+        self.audit_log()?;
+        if let Some(audit) = &mut self.audit {
+            audit.clear();
+        }
+        Ok(())
+    }
+
+    /// Counts per source since the index was created or loaded, keyed by the
+    /// `key` metadata value or, with `id_separator`, the id up to its first
+    /// separator (`None` when missing). `items` is the number of indexed
+    /// entries, `duplicates` the inserts that matched an entry, whether
+    /// rejected or added anyway, and `matched` how often the source's entries
+    /// were the match.
+    #[pyo3(signature = (key = None, id_separator = None))]
+    fn stats_by(
+        &self,
+        key: Option<&str>,
+        id_separator: Option<&str>,
+    ) -> PyResult<FnvHashMap<Option<String>, FnvHashMap<&'static str, usize>>> {
+        let valid = match (key, id_separator) {
+            (Some(_), None) => true,
+            (None, Some(sep)) => !sep.is_empty(),
+            _ => false,
+        };
+        if !valid {
+            return Err(PyValueError::new_err(
+                "exactly one of key or a non-empty id_separator is required",
+            ));
+        }
+        let source_of = |id: &str, metadata: &FnvHashMap<String, String>| match key {
+            Some(key) => metadata.get(key).cloned(),
+            None => id
+                .split_once(id_separator.unwrap())
+                .map(|(prefix, _)| prefix.to_string()),
+        };
+        let mut stats: FnvHashMap<Option<String>, [usize; 3]> = FnvHashMap::default();
+        for (id, entry) in self.ids.iter().zip(self.entries.iter()) {
+            let counts = stats.entry(source_of(id, &entry.metadata)).or_default();
+            counts[0] += 1;
+            counts[1] += entry.duplicate as usize;
+            counts[2] += entry.matched;
+        }
+        for (id, metadata) in &self.rejected {
+            stats.entry(source_of(id, metadata)).or_default()[1] += 1;
+        }
+        Ok(stats
+            .into_iter()
+            .map(|(source, counts)| {
+                let names = ["items", "duplicates", "matched"];
+                (source, names.into_iter().zip(counts).collect())
+            })
+            .collect())
+    }
+
+    /// `(sequence number, id, signature)` of every entry in insertion order.
+    fn items(&self) -> PyResult<Vec<(u64, String, Vec<f32>)>> {
+        self.check_signatures_kept()?;
+        let mut order: Vec<usize> = (0..self.ids.len()).collect();
+        order.sort_by_key(|&i| self.entries[i].seq);
+        order
+            .into_iter()
+            .map(|i| Ok((self.entries[i].seq, self.ids[i].clone(), self.signature(i)?)))
+            .collect()
+    }
+
+    pub(crate) fn length(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// The `k` buckets shared by the most documents, as `(key, documents,
+    /// example ids)` with up to `examples` ids in insertion order. Equal slot
+    /// values almost always come from the same shingle winning a slot, so
+    /// large buckets point at boilerplate shingles. With `hashed_buckets`,
+    /// the key is the 8-byte bucket hash rather than slot value bytes.
+    #[pyo3(signature = (k = 10, examples = 5))]
+    fn heavy_hitters<'py>(
+        &self,
+        py: Python<'py>,
+        k: usize,
+        examples: usize,
+    ) -> Vec<(Bound<'py, PyBytes>, usize, Vec<String>)> {
+        let key_len = if self.hashed_buckets {
+            8
+        } else {
+            self.key_bytes
+        };
+        let mut buckets: Vec<_> = self
+            .candidates
+            .iter()
+            .map(|(key, members)| (key.to_le_bytes()[..key_len].to_vec(), members))
+            .collect();
+        buckets.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then_with(|| a.0.cmp(&b.0)));
+        buckets
+            .into_iter()
+            .take(k)
+            .map(|(key, members)| {
+                let mut first: Vec<usize> = members.iter().copied().collect();
+                first.sort_unstable();
+                let ids = first
+                    .into_iter()
+                    .take(examples)
+                    .map(|i| self.ids[i].clone())
+                    .collect();
+                (PyBytes::new(py, &key), members.len(), ids)
+            })
+            .collect()
+    }
+
+    /// The complete index as plain Python values and bytes, restorable with
+    /// `LSH.from_dict`.
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        self.state(py)
+    }
+
+    #[staticmethod]
+    fn from_dict(state: &Bound<'_, PyDict>) -> PyResult<Self> {
+        LSH::from_state(state)
+    }
+
+    fn fingerprint(&self) -> Option<u64> {
+        self.fingerprint
+    }
+
+    fn get_metadata(&self, id: &str) -> Option<FnvHashMap<String, String>> {
+        self.id_map
+            .get(id)
+            .map(|&i| self.entries[i].metadata.clone())
+    }
+
+    fn get_timestamp(&self, id: &str) -> Option<f64> {
+        self.id_map.get(id).map(|&i| self.entries[i].timestamp)
+    }
+
+    fn get_weight(&self, id: &str) -> Option<f64> {
+        self.id_map.get(id).map(|&i| self.entries[i].weight)
+    }
+
+    fn get_language(&self, id: &str) -> Option<String> {
+        self.id_map
+            .get(id)
+            .and_then(|&i| self.entries[i].language.clone())
+    }
+
+    /// Label of the duplicate cluster containing `id`: the index of the
+    /// oldest entry in that cluster.
+    fn cluster_of(&self, id: &str) -> Option<usize> {
+        self.id_map.get(id).map(|&i| self.clusters.find(i))
+    }
+
+    /// Merges the clusters of known duplicate pairs. Pairs whose ids are not
+    /// indexed yet are remembered and applied once both ids are present.
+    /// Returns how many pairs were applied immediately.
+    fn link_pairs(&mut self, pairs: Vec<(String, String)>) -> usize {
+        let mut applied = 0;
+        for (a, b) in pairs {
+            match (self.id_map.get(&a), self.id_map.get(&b)) {
+                (Some(&i), Some(&j)) => {
+                    Arc::make_mut(&mut self.clusters).union(i, j);
+                    applied += 1;
+                }
+                _ => {
+                    Arc::make_mut(&mut self.pending_links)
+                        .entry(a.clone())
+                        .or_default()
+                        .push(b.clone());
+                    Arc::make_mut(&mut self.pending_links)
+                        .entry(b)
+                        .or_default()
+                        .push(a);
+                }
+            }
+        }
+        applied
+    }
+
+    fn cluster_members(&self, label: usize) -> PyResult<Vec<String>> {
+        if label >= self.ids.len() {
+            return Err(PyValueError::new_err("unknown cluster label"));
+        }
+        Ok(self
+            .clusters
+            .members(label)
+            .iter()
+            .map(|&i| self.ids[i].clone())
+            .collect())
+    }
+
+    /// `weight`, the total weight of the cluster's entries, and `absorbed`,
+    /// that of the duplicates it rejected, each counted once at its best
+    /// match.
+    fn cluster_weight(&self, label: usize) -> PyResult<FnvHashMap<String, f64>> {
+        if label >= self.ids.len() {
+            return Err(PyValueError::new_err("unknown cluster label"));
+        }
+        let members = self.clusters.members(label);
+        let weight = members.iter().map(|&i| self.entries[i].weight).sum();
+        let absorbed = members.iter().map(|&i| self.entries[i].absorbed).sum();
+        let mut totals = FnvHashMap::default();
+        totals.insert("weight".to_string(), weight);
+        totals.insert("absorbed".to_string(), absorbed);
+        Ok(totals)
+    }
+
+    /// Entries at least `threshold` similar to `data`, as a `CheckResult`.
+    /// `rerank` is called as `rerank(None, candidate_id, similarity)` for
+    /// every match, see `Rerank`. With `time_budget_ms`, verification stops
+    /// once the budget is spent and the result's `truncated` tells whether
+    /// candidates were left unchecked. With
+    /// `id_prefix`, only ids starting with it are verified and returned. With
+    /// `max_results`, at most that many matches are returned and the
+    /// result's `overflow` tells whether there were more.
+    /// `language_thresholds` maps language tags to the threshold entries
+    /// tagged with them must reach instead of `threshold`, see
+    /// `check_and_add(language=...)`. With `max_length_ratio`, entries whose
+    /// shingle count differs from the query's `shingles` by more than that
+    /// factor are not matched, however similar; entries added without a
+    /// count always can be.
+    #[pyo3(signature = (data, threshold = 0.5, filter = None, since = None, until = None, fingerprint = None, rerank = None, time_budget_ms = None, id_prefix = None, max_results = None, language_thresholds = None, max_length_ratio = None, shingles = None))]
+    #[inline]
+    #[allow(clippy::too_many_arguments)]
+    fn check<'py>(
+        &self,
+        py: Python<'py>,
+        data: Vec<f32>,
+        threshold: f64,
+        filter: Option<&Bound<'py, PyAny>>,
+        since: Option<f64>,
+        until: Option<f64>,
+        fingerprint: Option<u64>,
+        rerank: Option<&Bound<'py, PyAny>>,
+        time_budget_ms: Option<f64>,
+        id_prefix: Option<String>,
+        max_results: Option<usize>,
+        language_thresholds: Option<FnvHashMap<String, f64>>,
+        max_length_ratio: Option<f64>,
+        shingles: Option<usize>,
+    ) -> PyResult<CheckResult> {
+        self.check_fingerprint(fingerprint)?;
+        let filter = CandidateFilter::new(filter, since, until)?
+            .with_rerank(rerank, || Ok(py.None().into_bound(py)))?
+            .with_time_budget(time_budget_ms)?
+            .with_id_prefix(id_prefix)
+            .with_max_results(max_results)
+            .with_language_thresholds(language_thresholds)
+            .with_max_length_ratio(max_length_ratio, shingles)?;
+        let matches = self.query(&to_bytes(&data), threshold, &filter)?;
+        Ok(CheckResult::new(self.to_id_map(&matches), None)
+            .with_truncated(filter.truncated.get())
+            .with_overflow(filter.overflow.get()))
+    }
+
+    /// The `k` entries most similar to `data` among those reaching
+    /// `threshold`, as `(id, similarity)` pairs best first.
+    #[pyo3(signature = (data, k = 10, threshold = 0.0, fingerprint = None))]
+    fn top_k(
+        &self,
+        data: Vec<f32>,
+        k: usize,
+        threshold: f64,
+        fingerprint: Option<u64>,
+    ) -> PyResult<Vec<(String, f64)>> {
+        self.check_fingerprint(fingerprint)?;
+        self.top_k_of(&to_bytes(&data), k, threshold)
+    }
+
+    /// A read-only snapshot of the index as it is now, see `LSHReader`. It
+    /// shares the entry data, so taking one is cheap; later changes to this
+    /// index do not show in it.
+    fn reader(&self) -> LSHReader {
+        LSHReader {
+            index: RwLock::new(Arc::new(self.snapshot())),
+        }
+    }
+
+    /// Number of indexed entries at least `threshold` similar to `data`,
+    /// without building the id mapping `check` returns.
+    #[pyo3(signature = (data, threshold = 0.5))]
+    fn count_matches(&self, data: Vec<f32>, threshold: f64) -> PyResult<usize> {
+        Ok(self
+            .query(&to_bytes(&data), threshold, &CandidateFilter::default())?
+            .len())
+    }
+
+    /// 1 minus the similarity of the closest indexed entry, so 1.0 for
+    /// anything new and 0.0 for an exact duplicate. Nothing is inserted.
+    fn novelty(&self, data: Vec<f32>) -> PyResult<f64> {
+        Ok(1.0 - self.best_match(&to_bytes(&data))?.map_or(0.0, |(_, s)| s))
+    }
+
+    /// `rerank` is called as `rerank(new_id, candidate_id, similarity)` before
+    /// deciding whether to insert, so dropping every match lets the item in.
+    /// `max_results` caps the matches as in `check`. A rejected insert adds
+    /// its `weight` (default 1) to what its best match absorbed, see
+    /// `cluster_weight`; `weights` of the result holds those of the matches.
+    /// An inserted entry is tagged with `language`, which later queries can
+    /// give a threshold of its own in `language_thresholds` as in `check`.
+    /// `shingles` is the number of shingles `data` was sketched from, stored
+    /// with the entry and compared against with `max_length_ratio` as in
+    /// `check`. `time_budget_ms` and `id_prefix` apply as in `check`; when
+    /// verification runs out of time the item is not added, unless
+    /// `add_if_dup` is set, and `truncated` of the result is set. Entries
+    /// outside `id_prefix` are not matched, so they never keep an item out.
+    #[pyo3(
+        signature = (new_id, data, threshold = 0.5, add_if_dup = false, metadata = None, filter = None, since = None, until = None, timestamp = None, fingerprint = None, rerank = None, max_results = None, weight = None, language = None, language_thresholds = None, shingles = None, max_length_ratio = None, time_budget_ms = None, id_prefix = None),
+    )]
+    #[inline]
+    #[allow(clippy::too_many_arguments)]
+    fn check_and_add(
+        &mut self,
+        py: Python<'_>,
+        new_id: &str,
+        data: Vec<f32>,
+        threshold: f64,
+        add_if_dup: bool,
+        metadata: Option<FnvHashMap<String, String>>,
+        filter: Option<&Bound<'_, PyAny>>,
+        since: Option<f64>,
+        until: Option<f64>,
+        timestamp: Option<f64>,
+        fingerprint: Option<u64>,
+        rerank: Option<&Bound<'_, PyAny>>,
+        max_results: Option<usize>,
+        weight: Option<f64>,
+        language: Option<String>,
+        language_thresholds: Option<FnvHashMap<String, f64>>,
+        shingles: Option<usize>,
+        max_length_ratio: Option<f64>,
+        time_budget_ms: Option<f64>,
+        id_prefix: Option<String>,
+    ) -> PyResult<CheckResult> {
+        self.check_fingerprint(fingerprint)?;
+        let filter = CandidateFilter::new(filter, since, until)?
+            .with_rerank(rerank, || Ok(new_id.into_pyobject(py)?.into_any()))?
+            .with_time_budget(time_budget_ms)?
+            .with_id_prefix(id_prefix)
+            .with_max_results(max_results)
+            .with_language_thresholds(language_thresholds)
+            .with_max_length_ratio(max_length_ratio, shingles)?;
+        let mut info = EntryInfo::new(metadata, timestamp)
+            .with_weight(weight)?
+            .with_language(language);
+        info.shingles = shingles;
+        let (matches, index) = self.check_and_insert(
+            new_id,
+            to_bytes(&data),
+            threshold,
+            add_if_dup,
+            &filter,
+            info,
+        )?;
+        if index.is_some() {
+            self.fingerprint = self.fingerprint.or(fingerprint);
+        }
+        Ok(CheckResult::new(self.to_id_map(&matches), index)
+            .with_weights(self.weights_of(&matches))
+            .with_truncated(filter.truncated.get())
+            .with_overflow(filter.overflow.get()))
+    }
+
+    #[pyo3(signature = (data, metadata = None, timestamp = None, fingerprint = None))]
+    fn add_auto(
+        &mut self,
+        data: Vec<f32>,
+        metadata: Option<FnvHashMap<String, String>>,
+        timestamp: Option<f64>,
+        fingerprint: Option<u64>,
+    ) -> PyResult<String> {
+        self.check_fingerprint(fingerprint)?;
+        self.check_width(data.len(), true)?;
+        let new_id = self.next_id();
+        self.insert(
+            &new_id,
+            to_bytes(&data),
+            EntryInfo::new(metadata, timestamp),
+        )?;
+        self.fingerprint = self.fingerprint.or(fingerprint);
+        Ok(new_id)
+    }
+}
+
+/// Read-only view of an index snapshot taken by `LSH.reader()`, shared
+/// behind an `Arc` so `clone()` is cheap. Queries release the GIL, so
+/// threads can run them in parallel while the writable index stays with
+/// whoever ingests; take a fresh reader to see newer entries, or `reload` a
+/// rebuilt index into this one. Filters that call back into Python
+/// (`filter`, `rerank`) are not available.
+#[pyclass(frozen)]
+pub struct LSHReader {
+    index: RwLock<Arc<LSH>>,
+}
+
+impl LSHReader {
+    /// The snapshot queries run on now; a `reload` does not affect queries
+    /// that already took it.
+    fn current(&self) -> Arc<LSH> {
+        Arc::clone(&self.index.read().unwrap())
+    }
+}
+
+#[pymethods]
+impl LSHReader {
+    /// Like `LSH.check` without `filter` and `rerank`.
+    #[pyo3(signature = (data, threshold = 0.5, since = None, until = None, fingerprint = None, time_budget_ms = None, id_prefix = None, max_results = None, language_thresholds = None, max_length_ratio = None, shingles = None))]
+    #[allow(clippy::too_many_arguments)]
+    fn check<'py>(
+        &self,
+        py: Python<'py>,
+        data: Vec<f32>,
+        threshold: f64,
+        since: Option<f64>,
+        until: Option<f64>,
+        fingerprint: Option<u64>,
+        time_budget_ms: Option<f64>,
+        id_prefix: Option<String>,
+        max_results: Option<usize>,
+        language_thresholds: Option<FnvHashMap<String, f64>>,
+        max_length_ratio: Option<f64>,
+        shingles: Option<usize>,
+    ) -> PyResult<CheckResult> {
+        let index = &*self.current();
+        index.check_fingerprint(fingerprint)?;
+        let data_bytes = to_bytes(&data);
+        let (matches, truncated, overflow) = py.allow_threads(|| {
+            let filter = CandidateFilter::new(None, since, until)?
+                .with_time_budget(time_budget_ms)?
+                .with_id_prefix(id_prefix)
+                .with_max_results(max_results)
+                .with_language_thresholds(language_thresholds)
+                .with_max_length_ratio(max_length_ratio, shingles)?;
+            let matches = index.query(&data_bytes, threshold, &filter)?;
+            PyResult::Ok((
+                index.to_id_map(&matches),
+                filter.truncated.get(),
+                filter.overflow.get(),
+            ))
+        })?;
+        Ok(CheckResult::new(matches, None)
+            .with_truncated(truncated)
+            .with_overflow(overflow))
+    }
+
+    /// Like `LSH.top_k`.
+    #[pyo3(signature = (data, k = 10, threshold = 0.0, fingerprint = None))]
+    fn top_k(
+        &self,
+        py: Python<'_>,
+        data: Vec<f32>,
+        k: usize,
+        threshold: f64,
+        fingerprint: Option<u64>,
+    ) -> PyResult<Vec<(String, f64)>> {
+        let index = &*self.current();
+        index.check_fingerprint(fingerprint)?;
+        let data_bytes = to_bytes(&data);
+        py.allow_threads(|| index.top_k_of(&data_bytes, k, threshold))
+    }
+
+    /// Another handle on the current snapshot; later `reload`s of either
+    /// reader do not affect the other.
+    fn clone(&self) -> LSHReader {
+        LSHReader {
+            index: RwLock::new(self.current()),
+        }
+    }
+
+    /// Swaps in the index restored from `state`, an `LSH.to_dict()` or the
+    /// `"index"` entry of a `SuperMinHasherLSH.to_dict()`, such as a nightly
+    /// rebuild. The new index is built first and replaces the old one in a
+    /// single step: queries already running finish on the old snapshot and
+    /// later ones see only the new one. A state whose fingerprint differs
+    /// from the current index's is refused.
+    fn reload(&self, state: &Bound<'_, PyDict>) -> PyResult<()> {
+        let index = LSH::from_state(state)?;
+        let mut slot = self.index.write().unwrap();
+        if let (Some(current), Some(new)) = (slot.fingerprint, index.fingerprint) {
+            if current != new {
+                return Err(PyValueError::new_err(format!(
+                    "state fingerprint {:#018x} does not match the reader fingerprint {:#018x}",
+                    new, current
+                )));
+            }
+        }
+        *slot = Arc::new(index);
+        Ok(())
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.current().ids.to_vec()
+    }
+
+    fn length(&self) -> usize {
+        self.current().ids.len()
+    }
+
+    fn fingerprint(&self) -> Option<u64> {
+        self.current().fingerprint
+    }
+}
+
+/// Hands already computed shingle hashes to SuperMinHash unchanged.
+#[derive(Default)]
+pub(crate) struct PassThroughHasher(u64);
+
+impl Hasher for PassThroughHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 << 8) | b as u64;
+        }
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.0 = i;
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// FNV hash of a shingle, identical to hashing it as a `Vec<char>` or, for
+/// word shingles, a `Vec<&str>`.
+fn shingle_hash<T: Hash>(shingle: &[T]) -> u64 {
+    let mut h = FnvHasher::default();
+    shingle.hash(&mut h);
+    h.finish()
+}
+
+/// Hash function for shingles, chosen with the `hasher` option. FNV is the
+/// default and what existing signatures were built with; wyhash is faster
+/// on every shingle length, xxh3 on long ones and words. There is no aHash:
+/// its output may change between versions and platforms, which signatures
+/// kept across runs cannot afford.
+#[derive(Clone, Copy, PartialEq)]
+enum ShingleHasher {
+    Fnv,
+    Xxh3,
+    WyHash,
+}
+
+impl ShingleHasher {
+    const NAMES: [(&'static str, ShingleHasher); 3] = [
+        ("fnv", ShingleHasher::Fnv),
+        ("xxh3", ShingleHasher::Xxh3),
+        ("wyhash", ShingleHasher::WyHash),
+    ];
+
+    fn parse(name: &str) -> PyResult<Self> {
+        Self::NAMES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|&(_, hasher)| hasher)
+            .ok_or_else(|| PyValueError::new_err("hasher must be 'fnv', 'xxh3' or 'wyhash'"))
+    }
+
+    fn name(self) -> &'static str {
+        Self::NAMES.iter().find(|(_, h)| *h == self).unwrap().0
+    }
+
+    fn hash<T: Hash>(self, shingle: &[T]) -> u64 {
+        fn hash_with<T: Hash>(mut h: impl Hasher, shingle: &[T]) -> u64 {
+            shingle.hash(&mut h);
+            h.finish()
+        }
+        match self {
+            ShingleHasher::Fnv => shingle_hash(shingle),
+            ShingleHasher::Xxh3 => hash_with(XxHash3_64::default(), shingle),
+            ShingleHasher::WyHash => hash_with(WyHash::with_seed(0), shingle),
+        }
+    }
+
+    /// Hash of a slot index and the key bytes of its value, see
+    /// `LSH::bucket_key`.
+    fn hash_slot(self, slot: u32, bytes: &[u8]) -> u64 {
+        fn hash_with(mut h: impl Hasher, slot: u32, bytes: &[u8]) -> u64 {
+            h.write_u32(slot);
+            h.write(bytes);
+            h.finish()
+        }
+        match self {
+            ShingleHasher::Fnv => hash_with(FnvHasher::default(), slot, bytes),
+            ShingleHasher::Xxh3 => hash_with(XxHash3_64::default(), slot, bytes),
+            ShingleHasher::WyHash => hash_with(WyHash::with_seed(0), slot, bytes),
+        }
+    }
+}
+
+fn signature_hash(signature: &[f32]) -> u64 {
+    let mut h = FnvHasher::default();
+    for v in signature {
+        h.write_u32(v.to_bits());
+    }
+    h.finish()
+}
+
+/// Float type of the sketch values, chosen with the `precision` option. f64
+/// values collide less often on very large shingle sets, but indexes and
+/// sketch states only take f32 signatures.
+#[derive(Clone, Copy, PartialEq)]
+enum Precision {
+    F32,
+    F64,
+}
+
+impl Precision {
+    const NAMES: [(&'static str, Precision); 2] =
+        [("f32", Precision::F32), ("f64", Precision::F64)];
+
+    fn parse(name: &str) -> PyResult<Self> {
+        Self::NAMES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|&(_, precision)| precision)
+            .ok_or_else(|| PyValueError::new_err("precision must be 'f32' or 'f64'"))
+    }
+
+    fn name(self) -> &'static str {
+        Self::NAMES.iter().find(|(_, p)| *p == self).unwrap().0
+    }
+}
+
+pub(crate) enum Sketcher {
+    F32(SuperMinHash<f32, u64, PassThroughHasher>),
+    F64(SuperMinHash<f64, u64, PassThroughHasher>),
+}
+
+impl Sketcher {
+    /// A sketcher of f32 signatures, as indexes store them.
+    pub(crate) fn f32(size: usize) -> Self {
+        Sketcher::new(size, Precision::F32)
+    }
+
+    fn new(size: usize, precision: Precision) -> Self {
+        let bh = BuildHasherDefault::<PassThroughHasher>::default();
+        match precision {
+            Precision::F32 => Sketcher::F32(SuperMinHash::new(size, bh)),
+            Precision::F64 => Sketcher::F64(SuperMinHash::new(size, bh)),
+        }
+    }
+
+    pub(crate) fn sketch(&mut self, h: u64) {
+        match self {
+            Sketcher::F32(minhash) => minhash.sketch(&h).unwrap(),
+            Sketcher::F64(minhash) => minhash.sketch(&h).unwrap(),
+        }
+    }
+
+    pub(crate) fn reinit(&mut self) {
+        match self {
+            Sketcher::F32(minhash) => minhash.reinit(),
+            Sketcher::F64(minhash) => minhash.reinit(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Sketcher::F32(minhash) => minhash.get_hsketch().len(),
+            Sketcher::F64(minhash) => minhash.get_hsketch().len(),
+        }
+    }
+
+    /// The current sketch values.
+    pub(crate) fn signature(&self) -> Signature {
+        match self {
+            Sketcher::F32(minhash) => Signature::F32(minhash.get_hsketch().to_vec()),
+            Sketcher::F64(minhash) => Signature::F64(minhash.get_hsketch().to_vec()),
+        }
+    }
+}
+
+/// Sketch values in the precision they were computed in.
+#[derive(Clone)]
+pub(crate) enum Signature {
+    F32(Vec<f32>),
+    F64(Vec<f64>),
+}
+
+impl Signature {
+    /// The f32 values everything but `finalize` works with.
+    pub(crate) fn into_f32(self) -> PyResult<Vec<f32>> {
+        match self {
+            Signature::F32(values) => Ok(values),
+            Signature::F64(_) => Err(PyValueError::new_err(
+                "precision='f64' signatures can only be finalized; indexes and sketch states \
+                 take f32 ones",
+            )),
+        }
+    }
+
+    fn precision(&self) -> Precision {
+        match self {
+            Signature::F32(_) => Precision::F32,
+            Signature::F64(_) => Precision::F64,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Signature::F32(values) => values.len(),
+            Signature::F64(values) => values.len(),
+        }
+    }
+
+    fn truncate(&mut self, len: usize) {
+        match self {
+            Signature::F32(values) => values.truncate(len),
+            Signature::F64(values) => values.truncate(len),
+        }
+    }
+
+    /// Estimated number of distinct shingles sketched. On its own each slot
+    /// holds the least of one uniform draw from [0, m) per shingle, so
+    /// -ln(1 - value / m) is exponential with rate n and (m - 1) over their
+    /// sum estimates n. Empty slots still hold the initial value, beyond m.
+    fn estimated_cardinality(&self) -> f64 {
+        let values: Vec<f64> = match self {
+            Signature::F32(values) => values.iter().map(|&v| v as f64).collect(),
+            Signature::F64(values) => values.clone(),
+        };
+        let m = values.len() as f64;
+        if values.iter().any(|&v| v >= m) {
+            return 0.0;
+        }
+        let sum: f64 = values
+            .iter()
+            .map(|v| -(1.0 - v / m).max(f64::MIN_POSITIVE).ln())
+            .sum();
+        (m - 1.0) / sum
+    }
+
+    /// `signature_hash` of either precision.
+    fn hash(&self) -> u64 {
+        match self {
+            Signature::F32(values) => signature_hash(values),
+            Signature::F64(values) => {
+                let mut h = FnvHasher::default();
+                for v in values {
+                    h.write_u64(v.to_bits());
+                }
+                h.finish()
+            }
+        }
+    }
+
+    pub(crate) fn into_py(self, py: Python<'_>) -> PyResult<PyObject> {
+        match self {
+            Signature::F32(values) => values.into_py_any(py),
+            Signature::F64(values) => values.into_py_any(py),
+        }
+    }
+}
+
+/// Distinct-count estimates accumulated across every sketch of a hasher.
+struct DistinctCounts {
+    shingles: HyperLogLog,
+    documents: HyperLogLog,
+}
+
+/// The normalization and shingling settings of a hasher, kept apart from
+/// the sketch state so texts can be shingled from several threads.
+pub(crate) struct TextConfig {
+    n_gram: usize,
+    lowercase: bool,
+    unicode_normalize: bool,
+    /// The Chinese variant text is converted to, `None` to leave it as is.
+    zh_variant: Option<Variant>,
+    punct_norm: bool,
+    /// Replacement for `SP_PUNCT_RE` in the punct_norm step.
+    punct_re: Option<regex::Regex>,
+    /// When set, only the text matching it is kept, see `keep_chars_regex`.
+    keep_re: Option<regex::Regex>,
+    /// Whether combining marks are removed, so "café" shingles as "cafe".
+    strip_accents: bool,
+    short_text: ShortText,
+    /// Whether the text is wrapped in `BEGIN_SENTINEL` and `END_SENTINEL`
+    /// before shingling, so prefixes and suffixes get shingles of their own.
+    anchor_ends: bool,
+    /// Whether shingles are `n_gram` whitespace-separated words rather than
+    /// characters.
+    words: bool,
+    /// Normalized words dropped before shingling, see `remove_stopwords`.
+    stopwords: Option<FnvHashSet<String>>,
+    /// Whether HTML markup is removed before normalizing, see `HtmlStripper`.
+    strip_html: bool,
+    /// Whether URLs and email addresses are replaced by `URL_TOKEN` and
+    /// `EMAIL_TOKEN`.
+    mask_urls: bool,
+    mask_emails: bool,
+    /// Python callable applied last, see `Preprocessor`.
+    preprocessor: Option<Preprocessor>,
+    /// Whether emoji are removed together with the variation selectors,
+    /// joiners, skin tone modifiers and tags that build emoji sequences.
+    strip_emoji: bool,
+    /// Whether katakana, full or half width, is folded into hiragana.
+    kana_fold: bool,
+    /// With `token_mode="char+word"`, the word n-grams also fed to the
+    /// character sketch.
+    char_word: Option<CharWord>,
+    /// Mixed into every shingle hash, so hashers with different seeds draw
+    /// independent permutations.
+    seed: Option<u64>,
+    hasher: ShingleHasher,
+    limits: TextLimits,
+}
+
+/// Bounds on untrusted input, applied to the raw text before any other
+/// normalization so pathological inputs cost no more than ordinary ones.
+struct TextLimits {
+    /// Longer texts, counted in UTF-8 bytes, are rejected with an error.
+    max_text_bytes: Option<usize>,
+    /// Runs of non-whitespace characters are cut to this many characters.
+    max_word_len: Option<usize>,
+    /// Runs of one repeated character are cut to this many repeats.
+    max_char_run: Option<usize>,
+}
+
+impl TextLimits {
+    fn new(
+        max_text_bytes: Option<usize>,
+        max_word_len: Option<usize>,
+        max_char_run: Option<usize>,
+    ) -> PyResult<Self> {
+        if [max_text_bytes, max_word_len, max_char_run].contains(&Some(0)) {
+            return Err(PyValueError::new_err(
+                "max_text_bytes, max_word_len and max_char_run must be greater than 0",
+            ));
+        }
+        Ok(TextLimits {
+            max_text_bytes,
+            max_word_len,
+            max_char_run,
+        })
+    }
+
+    fn check_len(&self, len: usize) -> PyResult<()> {
+        match self.max_text_bytes {
+            Some(max) if len > max => Err(PyValueError::new_err(format!(
+                "text of {} bytes exceeds max_text_bytes={}",
+                len, max
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    fn to_dict(&self) -> FnvHashMap<String, Option<usize>> {
+        [
+            ("max_text_bytes", self.max_text_bytes),
+            ("max_word_len", self.max_word_len),
+            ("max_char_run", self.max_char_run),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect()
+    }
+
+    fn apply(&self, s: String) -> PyResult<String> {
+        self.check_len(s.len())?;
+        let limited = match self.apply_piece(&mut LimitState::default(), &s)? {
+            Cow::Owned(limited) => Some(limited),
+            Cow::Borrowed(_) => None,
+        };
+        Ok(limited.unwrap_or(s))
+    }
+
+    /// `apply` to the next piece of a text read in parts, with the byte
+    /// total, word and run so far carried in `state`, so any split of the
+    /// text gives the same result. The text is known to be too long as soon
+    /// as the pieces so far are.
+    fn apply_piece<'a>(&self, state: &mut LimitState, s: &'a str) -> PyResult<Cow<'a, str>> {
+        state.bytes += s.len();
+        if let Some(max) = self.max_text_bytes.filter(|&max| state.bytes > max) {
+            return Err(PyValueError::new_err(format!(
+                "text of at least {} bytes exceeds max_text_bytes={}",
+                state.bytes, max
+            )));
+        }
+        if self.max_word_len.is_none() && self.max_char_run.is_none() {
+            return Ok(Cow::Borrowed(s));
+        }
+        let mut out = String::with_capacity(s.len());
+        for c in s.chars() {
+            state.word = if c.is_whitespace() { 0 } else { state.word + 1 };
+            state.run = if state.last == Some(c) {
+                state.run + 1
+            } else {
+                1
+            };
+            state.last = Some(c);
+            if self.max_word_len.is_some_and(|max| state.word > max)
+                || self.max_char_run.is_some_and(|max| state.run > max)
+            {
+                continue;
+            }
+            out.push(c);
+        }
+        Ok(Cow::Owned(out))
+    }
+}
+
+/// Progress of `TextLimits::apply_piece` through a text read in parts.
+#[derive(Default)]
+struct LimitState {
+    bytes: usize,
+    word: usize,
+    run: usize,
+    last: Option<char>,
+}
+
+/// Word n-grams sketched alongside character n-grams. Each is fed `weight`
+/// times under distinct salts, so it counts as that many set elements
+/// against one per character n-gram.
+struct CharWord {
+    n_gram: usize,
+    weight: u32,
+}
+
+/// Keeps word n-gram hashes of the combined mode apart from character ones.
+const WORD_GRAM_SALT: u64 = 0x776f_7264_6772_616d;
+
+impl CharWord {
+    fn feed(&self, h: u64, f: &mut impl FnMut(u64)) {
+        for k in 0..self.weight as u64 {
+            f(mix(h ^ WORD_GRAM_SALT.wrapping_add(k)));
+        }
+    }
+}
+
+/// Half-width katakana widened by NFKC and katakana mapped to the hiragana
+/// of the same sound, so "カタカナ", "ｶﾀｶﾅ" and "かたかな" shingle alike.
+fn fold_kana(s: &str) -> String {
+    let widened = HALFWIDTH_KANA_RE.replace_all(s, |caps: &regex::Captures| {
+        ICU_NORMALIZER.normalize(&caps[0])
+    });
+    widened
+        .chars()
+        .map(|c| match c {
+            '\u{30A1}'..='\u{30F6}' | '\u{30FD}' | '\u{30FE}' => {
+                char::from_u32(c as u32 - 0x60).unwrap()
+            }
+            c => c,
+        })
+        .collect()
+}
+
+/// A Python `str -> str` callable run on the normalized text just before
+/// shingling. Its module and qualified name stand in for it in the
+/// fingerprint.
+struct Preprocessor {
+    callable: Py<PyAny>,
+    name: String,
+}
+
+impl Preprocessor {
+    fn new(callable: Bound<'_, PyAny>) -> PyResult<Self> {
+        if !callable.is_callable() {
+            return Err(PyTypeError::new_err("preprocessor must be callable"));
+        }
+        let attr = |name| -> PyResult<String> {
+            match callable.getattr(name) {
+                Ok(value) => Ok(value.str()?.to_string()),
+                Err(_) => Ok(String::new()),
+            }
+        };
+        let name = format!("{}.{}", attr("__module__")?, attr("__qualname__")?);
+        Ok(Preprocessor {
+            callable: callable.unbind(),
+            name,
+        })
+    }
+
+    fn apply(&self, s: String) -> PyResult<String> {
+        Python::with_gil(|py| {
+            self.callable.call1(py, (s,))?.extract(py).map_err(|_| {
+                PyTypeError::new_err(format!("preprocessor {} must return a str", self.name))
+            })
+        })
+    }
+}
+
+/// Private-use characters standing in for masked URLs and emails, so they
+/// survive punctuation normalization and never occur in ordinary text.
+const URL_TOKEN: &str = "\u{e000}";
+const EMAIL_TOKEN: &str = "\u{e001}";
+
+const BEGIN_SENTINEL: char = '\u{2}';
+const END_SENTINEL: char = '\u{3}';
+
+/// Whether `token_mode` shingles words, and whether it adds word n-grams to
+/// character ones.
+fn parse_token_mode(token_mode: &str) -> PyResult<(bool, bool)> {
+    match token_mode {
+        "char" => Ok((false, false)),
+        "word" => Ok((true, false)),
+        "char+word" => Ok((false, true)),
+        _ => Err(PyValueError::new_err(
+            "token_mode must be 'char', 'word' or 'char+word'",
+        )),
+    }
+}
+
+/// How texts shorter than `n_gram` characters after normalization are
+/// shingled.
+#[derive(Clone, Copy, PartialEq)]
+enum ShortText {
+    /// The whole text is a single shingle.
+    Whole,
+    /// The text is padded with NUL characters to one `n_gram` shingle.
+    Pad,
+    /// Shingles of half the `n_gram` (at least 1), or the whole text if it
+    /// is shorter still.
+    Shrink,
+    Raise,
+    /// The text adds no shingles. Indexes neither check nor add a text left
+    /// without any, see `CheckResult.skipped`.
+    Skip,
+}
+
+impl ShortText {
+    const NAMES: [(&'static str, ShortText); 5] = [
+        ("whole", ShortText::Whole),
+        ("pad", ShortText::Pad),
+        ("shrink", ShortText::Shrink),
+        ("raise", ShortText::Raise),
+        ("skip", ShortText::Skip),
+    ];
+
+    fn parse(name: &str) -> PyResult<Self> {
+        Self::NAMES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|&(_, policy)| policy)
+            .ok_or_else(|| {
+                PyValueError::new_err(
+                    "short_text must be 'whole', 'pad', 'shrink', 'raise' or 'skip'",
+                )
+            })
+    }
+
+    fn name(self) -> &'static str {
+        Self::NAMES.iter().find(|(_, p)| *p == self).unwrap().0
+    }
+}
+
+/// Named alternatives to a custom `punct_pattern` regex.
+const PUNCT_PRESETS: &[(&str, &str)] = &[
+    ("default", r"[\s\p{Punctuation}]+"),
+    ("whitespace", r"\s+"),
+    ("keep_intraword", r"[\s\p{Punctuation}--['\-\u{2019}]]+"),
+];
+
+/// Compiles `punct_pattern`, which is either a preset name or a regex. The
+/// default pattern maps to `None` so it keeps using the shared regex.
+fn punct_regex(pattern: Option<&str>) -> PyResult<Option<regex::Regex>> {
+    let Some(pattern) = pattern else {
+        return Ok(None);
+    };
+    let pattern = PUNCT_PRESETS
+        .iter()
+        .find(|(name, _)| *name == pattern)
+        .map_or(pattern, |(_, preset)| preset);
+    if pattern == PUNCT_PRESETS[0].1 {
+        return Ok(None);
+    }
+    regex::Regex::new(pattern)
+        .map(Some)
+        .map_err(|e| PyValueError::new_err(format!("invalid punct_pattern: {}", e)))
+}
+
+/// Targets accepted by `zh_variant`.
+const ZH_VARIANTS: [(&str, Variant); 4] = [
+    ("hans", Variant::ZhHans),
+    ("hant", Variant::ZhHant),
+    ("tw", Variant::ZhTW),
+    ("hk", Variant::ZhHK),
+];
+
+/// Resolves `zh_variant`, which overrides `zh_conv` when given.
+fn zh_target(zh_conv: bool, zh_variant: Option<&str>) -> PyResult<Option<Variant>> {
+    match zh_variant {
+        None => Ok(zh_conv.then_some(Variant::ZhHans)),
+        Some("none") => Ok(None),
+        Some(name) => ZH_VARIANTS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|&(_, variant)| Some(variant))
+            .ok_or_else(|| {
+                PyValueError::new_err("zh_variant must be 'hans', 'hant', 'tw', 'hk' or 'none'")
+            }),
+    }
+}
+
+fn zh_variant_name(variant: Option<Variant>) -> &'static str {
+    ZH_VARIANTS
+        .iter()
+        .find(|(_, v)| Some(*v) == variant)
+        .map_or("none", |(name, _)| name)
+}
+
+/// Named alternatives to a custom `keep_chars` regex. Whitespace is kept so
+/// words do not run together.
+const KEEP_PRESETS: &[(&str, &str)] = &[
+    ("letters", r"[\p{L}\p{M}\s]+"),
+    ("alphanumeric", r"[\p{L}\p{M}\p{N}\s]+"),
+];
+
+/// Compiles `keep_chars`, which is either a preset name or a regex, usually
+/// a character class such as `[\p{Han}\s]`.
+fn keep_chars_regex(pattern: Option<&str>) -> PyResult<Option<regex::Regex>> {
+    let Some(pattern) = pattern else {
+        return Ok(None);
+    };
+    let pattern = KEEP_PRESETS
+        .iter()
+        .find(|(name, _)| *name == pattern)
+        .map_or(pattern, |(_, preset)| preset);
+    regex::Regex::new(pattern)
+        .map(Some)
+        .map_err(|e| PyValueError::new_err(format!("invalid keep_chars: {}", e)))
+}
+
+impl TextConfig {
+    fn normalize(&self, s: String) -> PyResult<String> {
+        let s = self.limits.apply(s)?;
+        if self.strip_html {
+            self.normalize_stripped(strip_html(&s))
+        } else {
+            self.normalize_stripped(s)
+        }
+    }
+
+    /// `normalize` of text already passed through `strip_html` if enabled.
+    fn normalize_stripped(&self, mut s: String) -> PyResult<String> {
+        if self.strip_emoji {
+            s = EMOJI_RE.replace_all(&s, "").to_string();
+        }
+        if self.unicode_normalize {
+            s = ICU_NORMALIZER.normalize(&s);
+        }
+        if self.kana_fold {
+            s = fold_kana(&s);
+        }
+        if self.strip_accents {
+            let decomposed = ICU_NFD.normalize(&s);
+            s = ICU_NFC.normalize(&NONSPACING_MARK_RE.replace_all(&decomposed, ""));
+        }
+        if self.mask_urls {
+            s = URL_RE.replace_all(&s, URL_TOKEN).to_string();
+        }
+        if self.mask_emails {
+            s = EMAIL_RE.replace_all(&s, EMAIL_TOKEN).to_string();
+        }
+        if let Some(re) = &self.keep_re {
+            s = re.find_iter(&s).map(|m| m.as_str()).collect();
+        }
+        if self.punct_norm {
+            let re = self.punct_re.as_ref().unwrap_or(&SP_PUNCT_RE);
+            s = re.replace_all(&s, " ").to_string();
+        }
+        if let Some(variant) = self.zh_variant {
+            s = zhconv::converters::get_builtin_converter(variant).convert(&s);
+        }
+        if self.lowercase {
+            s = s.to_lowercase();
+        }
+        if let Some(stopwords) = &self.stopwords {
+            s = remove_stopwords(&s, stopwords);
+        }
+        match &self.preprocessor {
+            Some(preprocessor) => preprocessor.apply(s),
+            None => Ok(s),
+        }
+    }
+
+    /// Calls `f` with the hash of every shingle of the normalized text. Texts
+    /// shorter than `n_gram` are handled according to `short_text`; with
+    /// `Raise` the error comes before any call to `f`.
+    fn for_each_shingle(&self, s: String, mut f: impl FnMut(u64)) -> PyResult<()> {
+        self.for_each_shingle_at(s, |_, h| f(h))
+    }
+
+    /// Like `for_each_shingle`, also passing each shingle's offset in the
+    /// normalized text, counted in characters or, in word mode, words.
+    fn for_each_shingle_at(&self, s: String, mut f: impl FnMut(usize, u64)) -> PyResult<()> {
+        let mut f = |pos, h| f(pos, self.seeded(h));
+        let normalized = self.normalize(s)?;
+        if self.words {
+            let words = self.shingle_words(&normalized);
+            return self.for_each_window(&words, "\0", |pos, shingle| {
+                f(pos, self.hasher.hash(shingle))
+            });
+        }
+        let cs = self.shingle_chars(&normalized);
+        self.for_each_window(&cs, '\0', |pos, shingle| f(pos, self.hasher.hash(shingle)))?;
+        if let Some(cw) = &self.char_word {
+            let words = self.shingle_words(&normalized);
+            self.for_each_window_n(cw.n_gram, true, &words, "\0", |pos, shingle| {
+                cw.feed(self.hasher.hash(shingle), &mut |h| f(pos, h))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Like `shingle_chars`, for word mode. Words are split on Unicode
+    /// whitespace.
+    fn shingle_words<'a>(&self, normalized: &'a str) -> Vec<&'a str> {
+        let mut words: Vec<&str> = normalized.split_whitespace().collect();
+        if self.anchor_ends {
+            words.insert(0, "\u{2}");
+            words.push("\u{3}");
+        }
+        words
+    }
+
+    /// Display forms of the shingles of already normalized text, word
+    /// shingles joined by spaces.
+    fn shingle_strings(&self, normalized: &str) -> PyResult<Vec<String>> {
+        let mut shingles = Vec::new();
+        if self.words {
+            let words = self.shingle_words(normalized);
+            self.for_each_window(&words, "\0", |_, shingle| shingles.push(shingle.join(" ")))?;
+        } else {
+            let cs = self.shingle_chars(normalized);
+            self.for_each_window(&cs, '\0', |_, shingle| {
+                shingles.push(shingle.iter().collect())
+            })?;
+            if let Some(cw) = &self.char_word {
+                let words = self.shingle_words(normalized);
+                self.for_each_window_n(cw.n_gram, true, &words, "\0", |_, shingle| {
+                    shingles.push(shingle.join(" "))
+                })?;
+            }
+        }
+        Ok(shingles)
+    }
+
+    /// The characters shingled for already normalized text, including the
+    /// sentinels with `anchor_ends`. Offsets into it are one past offsets
+    /// into the normalized text in that case.
+    fn shingle_chars(&self, normalized: &str) -> Vec<char> {
+        if self.anchor_ends {
+            std::iter::once(BEGIN_SENTINEL)
+                .chain(normalized.chars())
+                .chain(std::iter::once(END_SENTINEL))
+                .collect()
+        } else {
+            normalized.chars().collect()
+        }
+    }
+
+    /// Shingles the text read from `reader` `chunk_size` bytes at a time as if
+    /// it were a single text. Each piece ends before a separator run, so it
+    /// normalizes as it would in context, and the last `n_gram - 1` units of
+    /// a piece open the next.
+    fn for_each_shingle_read(
+        &self,
+        mut reader: impl Read,
+        chunk_size: usize,
+        mut f: impl FnMut(u64),
+    ) -> PyResult<()> {
+        let mut f = |h| f(self.seeded(h));
+        let separators = if !self.punct_norm {
+            &*WHITESPACE_RE
+        } else {
+            self.punct_re.as_ref().unwrap_or(&SP_PUNCT_RE)
+        };
+        let mut window = UnitWindow::new(self, self.words, self.n_gram);
+        let mut word_window = self
+            .char_word
+            .as_ref()
+            .map(|cw| (cw, UnitWindow::new(self, true, cw.n_gram)));
+        let mut stripper = self.strip_html.then(HtmlStripper::default);
+        let mut limited = LimitState::default();
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut text = String::new();
+        let mut offset = 0;
+        let mut chunk = vec![0; chunk_size];
+        loop {
+            let n = reader.read(&mut chunk)?;
+            bytes.extend_from_slice(&chunk[..n]);
+            let valid = match std::str::from_utf8(&bytes) {
+                Ok(s) => s.len(),
+                Err(e) if e.error_len().is_none() && n > 0 => e.valid_up_to(),
+                Err(e) => {
+                    return Err(PyValueError::new_err(format!(
+                        "invalid UTF-8 at byte offset {}",
+                        offset + e.valid_up_to()
+                    )))
+                }
+            };
+            let decoded = std::str::from_utf8(&bytes[..valid]).unwrap();
+            let decoded = self.limits.apply_piece(&mut limited, decoded)?;
+            match &mut stripper {
+                Some(stripper) => stripper.feed(&decoded, &mut text),
+                None => text.push_str(&decoded),
+            }
+            bytes.drain(..valid);
+            offset += valid;
+            if n == 0 {
+                if let Some(stripper) = stripper.take() {
+                    stripper.finish(&mut text);
+                }
+                let piece = std::mem::take(&mut text);
+                self.push_piece(&mut window, &mut word_window, piece, &mut f)?;
+                window.finish(self, &mut f)?;
+                if let Some((cw, word_window)) = word_window {
+                    word_window.finish(self, |h| cw.feed(h, &mut f))?;
+                }
+                return Ok(());
+            }
+            let masked = self.masked_spans(&text);
+            let cut = separators
+                .find_iter(&text)
+                .map(|m| m.start())
+                .filter(|&start| masked.iter().all(|&(a, b)| start <= a || start >= b))
+                .last()
+                .unwrap_or(0);
+            let cut = if cut == 0 && text.len() >= MAX_READ_CARRY {
+                self.forced_cut(&text)
+            } else {
+                cut
+            };
+            if cut > 0 {
+                let rest = text.split_off(cut);
+                let piece = std::mem::replace(&mut text, rest);
+                self.push_piece(&mut window, &mut word_window, piece, &mut f)?;
+            }
+        }
+    }
+
+    /// Where to cut a carry that reached `MAX_READ_CARRY` without a
+    /// separator, keeping its last `n_gram - 1` characters, at least one, to
+    /// open the next piece. The cut can split a word, so such a run may not
+    /// shingle exactly as it would whole.
+    fn forced_cut(&self, text: &str) -> usize {
+        let keep = self.n_gram.max(2) - 1;
+        text.char_indices()
+            .rev()
+            .nth(keep - 1)
+            .map_or(0, |(i, _)| i)
+    }
+
+    /// Normalizes a `for_each_shingle_read` piece and emits every shingle it
+    /// completes in either window.
+    fn push_piece(
+        &self,
+        window: &mut UnitWindow,
+        word_window: &mut Option<(&CharWord, UnitWindow)>,
+        piece: String,
+        f: &mut impl FnMut(u64),
+    ) -> PyResult<()> {
+        let normalized = self.normalize_stripped(piece)?;
+        window.push(&normalized, f);
+        if let Some((cw, word_window)) = word_window {
+            word_window.push(&normalized, &mut |h| cw.feed(h, f));
+        }
+        Ok(())
+    }
+
+    /// Byte ranges of `text` that masking may still replace as a whole, so
+    /// `for_each_shingle_read` must not cut inside them: every URL or email,
+    /// and everything after the last whitespace, where one could continue
+    /// into the next chunk.
+    fn masked_spans(&self, text: &str) -> Vec<(usize, usize)> {
+        let mut spans = Vec::new();
+        if !self.mask_urls && !self.mask_emails {
+            return spans;
+        }
+        let open = text.rfind(char::is_whitespace).unwrap_or(0);
+        spans.push((open, text.len()));
+        for (enabled, re) in [(self.mask_urls, &*URL_RE), (self.mask_emails, &*EMAIL_RE)] {
+            if enabled {
+                spans.extend(re.find_iter(text).map(|m| (m.start(), m.end())));
+            }
+        }
+        spans
+    }
+
+    /// The shingle hash `h` as fed to the sketcher under `seed`.
+    fn seeded(&self, h: u64) -> u64 {
+        match self.seed {
+            Some(seed) => mix(h ^ seed),
+            None => h,
+        }
+    }
+
+    /// Sketches `s` with a sketcher of this configuration's hasher and resets
+    /// it for the next text. Also returns the number of shingles sketched.
+    pub(crate) fn sketch_into(
+        &self,
+        minhash: &mut Sketcher,
+        s: String,
+    ) -> PyResult<(Signature, usize)> {
+        let mut count = 0;
+        self.for_each_shingle(s, |h| {
+            minhash.sketch(h);
+            count += 1;
+        })?;
+        let signature = minhash.signature();
+        minhash.reinit();
+        Ok((signature, count))
+    }
+
+    /// Calls `f` with the offset and units (characters or words) of every
+    /// shingle of already normalized text. `pad` fills short texts with
+    /// `ShortText::Pad`.
+    fn for_each_window<T: Clone>(
+        &self,
+        cs: &[T],
+        pad: T,
+        f: impl FnMut(usize, &[T]),
+    ) -> PyResult<()> {
+        self.for_each_window_n(self.n_gram, self.words, cs, pad, f)
+    }
+
+    /// `for_each_window` with shingles of `n_gram` units, words if `words`.
+    fn for_each_window_n<T: Clone>(
+        &self,
+        n_gram: usize,
+        words: bool,
+        cs: &[T],
+        pad: T,
+        mut f: impl FnMut(usize, &[T]),
+    ) -> PyResult<()> {
+        let n = if cs.len() >= n_gram {
+            n_gram
+        } else {
+            match self.short_text {
+                ShortText::Whole => cs.len(),
+                ShortText::Pad => {
+                    let mut padded = cs.to_vec();
+                    padded.resize(n_gram, pad);
+                    f(0, &padded);
+                    return Ok(());
+                }
+                ShortText::Shrink => (n_gram / 2).clamp(1, cs.len().max(1)),
+                ShortText::Skip => return Ok(()),
+                ShortText::Raise => {
+                    return Err(PyValueError::new_err(format!(
+                        "text has {} {} after normalization, fewer than n_gram={}",
+                        cs.len(),
+                        if words { "words" } else { "characters" },
+                        n_gram
+                    )))
+                }
+            }
+        };
+        if cs.len() <= n {
+            f(0, cs);
+        } else {
+            for (pos, shingle) in cs.windows(n).enumerate() {
+                f(pos, shingle);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Drops every whitespace-separated word of `s` in `stopwords` together with
+/// the whitespace before it, so "a of b" becomes "a b" in both token modes.
+/// Removing the preceding rather than the following whitespace keeps
+/// `for_each_shingle_read` pieces, which start at a separator, consistent
+/// with the whole text.
+fn remove_stopwords(s: &str, stopwords: &FnvHashSet<String>) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    loop {
+        let word_start = rest
+            .find(|c: char| !c.is_whitespace())
+            .unwrap_or(rest.len());
+        let (space, tail) = rest.split_at(word_start);
+        let (word, tail) = tail.split_at(tail.find(char::is_whitespace).unwrap_or(tail.len()));
+        if word.is_empty() {
+            out.push_str(space);
+            return out;
+        }
+        if !stopwords.contains(word) {
+            out.push_str(space);
+            out.push_str(word);
+        }
+        rest = tail;
+    }
+}
+
+/// The tail of a text shingled piece by piece: the last `n_gram - 1` units
+/// of everything pushed, or all of it while no shingle is complete.
+struct UnitWindow {
+    units: Units,
+    n_gram: usize,
+    hasher: ShingleHasher,
+    emitted: bool,
+}
+
+enum Units {
+    Chars(Vec<char>),
+    Words(Vec<String>),
+}
+
+impl UnitWindow {
+    fn new(config: &TextConfig, words: bool, n_gram: usize) -> Self {
+        let units = if words {
+            Units::Words(Vec::new())
+        } else {
+            Units::Chars(Vec::new())
+        };
+        let mut window = UnitWindow {
+            units,
+            n_gram,
+            hasher: config.hasher,
+            emitted: false,
+        };
+        if config.anchor_ends {
+            window.extend("\u{2}");
+        }
+        window
+    }
+
+    fn extend(&mut self, normalized: &str) {
+        match &mut self.units {
+            Units::Chars(units) => units.extend(normalized.chars()),
+            Units::Words(units) => units.extend(normalized.split_whitespace().map(str::to_string)),
+        }
+    }
+
+    /// Emits every shingle completed by the normalized `piece`.
+    fn push(&mut self, normalized: &str, f: &mut impl FnMut(u64)) {
+        self.extend(normalized);
+        self.flush(f);
+    }
+
+    fn flush(&mut self, f: &mut impl FnMut(u64)) {
+        let n = self.n_gram;
+        fn flush_units<T: Hash>(
+            units: &mut Vec<T>,
+            n: usize,
+            hasher: ShingleHasher,
+            f: &mut impl FnMut(u64),
+        ) -> bool {
+            if units.len() < n {
+                return false;
+            }
+            for shingle in units.windows(n) {
+                f(hasher.hash(shingle));
+            }
+            units.drain(..units.len() + 1 - n);
+            true
+        }
+        let emitted = match &mut self.units {
+            Units::Chars(units) => flush_units(units, n, self.hasher, f),
+            Units::Words(units) => flush_units(units, n, self.hasher, f),
+        };
+        self.emitted |= emitted;
+    }
+
+    /// Emits the last shingle, or applies `short_text` when the whole text
+    /// never reached `n_gram` units.
+    fn finish(mut self, config: &TextConfig, mut f: impl FnMut(u64)) -> PyResult<()> {
+        if config.anchor_ends {
+            self.extend("\u{3}");
+        }
+        self.flush(&mut f);
+        if self.emitted {
+            return Ok(());
+        }
+        let (n, hasher) = (self.n_gram, self.hasher);
+        match &self.units {
+            Units::Chars(units) => config
+                .for_each_window_n(n, false, units, '\0', |_, shingle| f(hasher.hash(shingle))),
+            Units::Words(units) => {
+                let units: Vec<&str> = units.iter().map(String::as_str).collect();
+                config.for_each_window_n(
+                    n,
+                    true,
+                    &units,
+                    "\0",
+                    |_, shingle| f(hasher.hash(shingle)),
+                )
+            }
+        }
+    }
+}
+
+#[pyclass]
+pub struct SuperMinHasher {
+    minhash: Sketcher,
+    size: usize,
+    precision: Precision,
+    config: TextConfig,
+    distinct: Option<DistinctCounts>,
+    /// Shingles fed into the current sketch.
+    shingle_count: usize,
+    /// Sketch states merged in with `import_state`, applied at `finalize`.
+    imported: Option<Vec<f32>>,
+    /// With `cache_size`, signatures of recent texts sketched whole, so an
+    /// exact repeat skips normalization and shingling.
+    cache: Option<SignatureCache>,
+}
+
+impl SuperMinHasher {
+    pub(crate) fn shingles_at(&self, s: String) -> PyResult<Vec<(usize, u64)>> {
+        let mut shingles = Vec::new();
+        self.config
+            .for_each_shingle_at(s, |pos, h| shingles.push((pos, h)))?;
+        Ok(shingles)
+    }
+
+    /// Feeds already hashed shingles, as produced by `shingles_at`.
+    pub(crate) fn sketch_hashes(&mut self, hashes: impl IntoIterator<Item = u64>) {
+        for h in hashes {
+            if let Some(distinct) = &mut self.distinct {
+                distinct.shingles.insert(h);
+            }
+            self.shingle_count += 1;
+            self.minhash.sketch(h);
+        }
+    }
+
+    /// Finalizes and also returns how many shingles went into the sketch.
+    pub(crate) fn finalize_counted(&mut self) -> PyResult<(Vec<f32>, usize)> {
+        let count = self.shingle_count;
+        Ok((self.finish().into_f32()?, count))
+    }
+
+    /// The signature of everything fed so far, imported states included.
+    /// Those are f32, see `export_state`.
+    fn current(&self) -> Signature {
+        let mut s = self.minhash.signature();
+        if let (Some(imported), Signature::F32(values)) = (&self.imported, &mut s) {
+            crate::partial::merge_into(values, imported);
+        }
+        s
+    }
+
+    /// Returns the full signature and resets for the next document.
+    fn finish(&mut self) -> Signature {
+        let s = self.current();
+        self.imported = None;
+        self.minhash.reinit();
+        self.shingle_count = 0;
+        if let Some(distinct) = &mut self.distinct {
+            distinct.documents.insert(s.hash());
+        }
+        s
+    }
+
+    /// Whether `short_text="skip"` leaves a text of `count` shingles out.
+    /// Every entry point that checks or indexes texts goes through this.
+    pub(crate) fn skips(&self, count: usize) -> bool {
+        count == 0 && self.config.short_text == ShortText::Skip
+    }
+
+    /// `skips` for `text`, counting its shingles without sketching it.
+    pub(crate) fn skips_text(&self, text: &str) -> PyResult<bool> {
+        if self.config.short_text != ShortText::Skip {
+            return Ok(false);
+        }
+        let mut count = 0;
+        self.config
+            .for_each_shingle(text.to_string(), |_| count += 1)?;
+        Ok(self.skips(count))
+    }
+
+    pub(crate) fn config(&self) -> &TextConfig {
+        &self.config
+    }
+
+    pub(crate) fn new_sketcher(&self) -> Sketcher {
+        Sketcher::new(self.size, self.precision)
+    }
+
+    /// Sketches every text on the rayon pool with one sketcher per worker,
+    /// returning f32 signatures and shingle counts in input order. Distinct
+    /// counts are merged back when tracking is enabled.
+    pub(crate) fn sketch_parallel(
+        &mut self,
+        texts: Vec<String>,
+    ) -> PyResult<Vec<(Vec<f32>, usize)>> {
+        self.sketch_parallel_signatures(texts)?
+            .into_iter()
+            .map(|(signature, count)| Ok((signature.into_f32()?, count)))
+            .collect()
+    }
+
+    /// Signature and shingle count of `text` sketched on its own, leaving
+    /// the hasher's sketch, signature cache and distinct counts untouched.
+    pub(crate) fn sketch_detached(&self, text: String) -> PyResult<(Vec<f32>, usize)> {
+        let mut minhash = self.new_sketcher();
+        let mut count = 0;
+        self.config.for_each_shingle(text, |h| {
+            count += 1;
+            minhash.sketch(h);
+        })?;
+        Ok((minhash.signature().into_f32()?, count))
+    }
+
+    /// `sketch_parallel` in the configured precision.
+    fn sketch_parallel_signatures(
+        &mut self,
+        texts: Vec<String>,
+    ) -> PyResult<Vec<(Signature, usize)>> {
+        let track = self.distinct.is_some();
+        let this = &*self;
+        let workers: Vec<_> = texts
+            .into_par_iter()
+            .enumerate()
+            .fold(
+                || {
+                    (
+                        this.new_sketcher(),
+                        Vec::new(),
+                        track.then(|| HyperLogLog::new(14)),
+                    )
+                },
+                |(mut minhash, mut out, mut hll), (i, text)| {
+                    let mut count = 0;
+                    let sketched = this.config.for_each_shingle(text, |h| {
+                        if let Some(hll) = &mut hll {
+                            hll.insert(h);
+                        }
+                        count += 1;
+                        minhash.sketch(h);
+                    });
+                    out.push((i, sketched.map(|_| (minhash.signature(), count))));
+                    minhash.reinit();
+                    (minhash, out, hll)
+                },
+            )
+            .map(|(_, out, hll)| (out, hll))
+            .collect();
+        let len = workers.iter().map(|(out, _)| out.len()).sum();
+        let mut signatures = vec![(Signature::F32(Vec::new()), 0); len];
+        for (out, hll) in workers {
+            if let (Some(distinct), Some(hll)) = (&mut self.distinct, hll) {
+                distinct.shingles.merge(&hll);
+            }
+            for (i, signature) in out {
+                signatures[i] = signature?;
+            }
+        }
+        if let Some(distinct) = &mut self.distinct {
+            for (signature, _) in &signatures {
+                distinct.documents.insert(signature.hash());
+            }
+        }
+        Ok(signatures)
+    }
+}
+
+lazy_static! {
+    static ref ICU_NORMALIZER: icu::normalizer::ComposingNormalizer =
+        icu::normalizer::ComposingNormalizer::new_nfkc();
+    static ref SP_PUNCT_RE: regex::Regex = regex::Regex::new(r"[\s\p{Punctuation}]+").unwrap();
+    static ref WHITESPACE_RE: regex::Regex = regex::Regex::new(r"\s+").unwrap();
+    static ref EMOJI_RE: regex::Regex = regex::Regex::new(
+        r"[\p{Extended_Pictographic}\p{Emoji_Modifier}\p{Regional_Indicator}\u{200D}\u{20E3}\u{FE00}-\u{FE0F}\u{E0020}-\u{E007F}\u{E0100}-\u{E01EF}]+"
+    )
+    .unwrap();
+    static ref HALFWIDTH_KANA_RE: regex::Regex =
+        regex::Regex::new(r"[\u{FF61}-\u{FF9F}]+").unwrap();
+    static ref URL_RE: regex::Regex =
+        regex::Regex::new(r"(?i)\b(?:[a-z][a-z0-9+.-]*://|www\.)\S+").unwrap();
+    static ref EMAIL_RE: regex::Regex =
+        regex::Regex::new(r"[\p{L}\p{N}._%+-]+@[\p{L}\p{N}-]+(?:\.[\p{L}\p{N}-]+)+").unwrap();
+    static ref ICU_NFD: icu::normalizer::DecomposingNormalizer =
+        icu::normalizer::DecomposingNormalizer::new_nfd();
+    static ref ICU_NFC: icu::normalizer::ComposingNormalizer =
+        icu::normalizer::ComposingNormalizer::new_nfc();
+    static ref NONSPACING_MARK_RE: regex::Regex = regex::Regex::new(r"\p{Mn}+").unwrap();
+}
+
+#[pymethods]
+impl SuperMinHasher {
+    #[new]
+    #[pyo3(
+        signature = (size, n_gram = 5, lowercase = true, unicode_normalize = true, zh_conv = true, punct_norm = true, track_distinct = false, punct_pattern = None, short_text = "whole", anchor_ends = false, keep_chars = None, token_mode = "char", strip_accents = false, zh_variant = None, stopwords = None, strip_html = false, mask_urls = false, mask_emails = false, preprocessor = None, strip_emoji = false, kana_fold = false, word_n_gram = None, word_weight = None, cache_size = None, seed = None, hasher = "fnv", precision = "f32", max_text_bytes = None, max_word_len = None, max_char_run = None),
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        size: usize,
+        n_gram: usize,
+        lowercase: bool,
+        unicode_normalize: bool,
+        zh_conv: bool,
+        punct_norm: bool,
+        track_distinct: bool,
+        punct_pattern: Option<String>,
+        short_text: &str,
+        anchor_ends: bool,
+        keep_chars: Option<String>,
+        token_mode: &str,
+        strip_accents: bool,
+        zh_variant: Option<&str>,
+        stopwords: Option<FnvHashSet<String>>,
+        strip_html: bool,
+        mask_urls: bool,
+        mask_emails: bool,
+        preprocessor: Option<Bound<'_, PyAny>>,
+        strip_emoji: bool,
+        kana_fold: bool,
+        word_n_gram: Option<usize>,
+        word_weight: Option<u32>,
+        cache_size: Option<usize>,
+        seed: Option<u64>,
+        hasher: &str,
+        precision: &str,
+        max_text_bytes: Option<usize>,
+        max_word_len: Option<usize>,
+        max_char_run: Option<usize>,
+    ) -> PyResult<Self> {
+        if size == 0 {
+            return Err(PyValueError::new_err("size must be greater than 0"));
+        }
+        if n_gram == 0 {
+            return Err(PyValueError::new_err("n_gram must be greater than 0"));
+        }
+        if cache_size == Some(0) {
+            return Err(PyValueError::new_err("cache_size must be greater than 0"));
+        }
+        let (words, combined) = parse_token_mode(token_mode)?;
+        let char_word = if combined {
+            let n_gram = word_n_gram.unwrap_or(3);
+            let weight = word_weight.unwrap_or(1);
+            if n_gram == 0 || weight == 0 {
+                return Err(PyValueError::new_err(
+                    "word_n_gram and word_weight must be greater than 0",
+                ));
+            }
+            Some(CharWord { n_gram, weight })
+        } else {
+            if word_n_gram.is_some() || word_weight.is_some() {
+                return Err(PyValueError::new_err(
+                    "word_n_gram and word_weight require token_mode='char+word'",
+                ));
+            }
+            None
+        };
+        let precision = Precision::parse(precision)?;
+
+        let minhash = Sketcher::new(size, precision);
+        let mut config = TextConfig {
+            n_gram,
+            lowercase,
+            unicode_normalize,
+            zh_variant: zh_target(zh_conv, zh_variant)?,
+            punct_norm,
+            punct_re: punct_regex(punct_pattern.as_deref())?,
+            keep_re: keep_chars_regex(keep_chars.as_deref())?,
+            strip_accents,
+            short_text: ShortText::parse(short_text)?,
+            anchor_ends,
+            words,
+            stopwords: None,
+            strip_html,
+            mask_urls,
+            mask_emails,
+            preprocessor: None,
+            strip_emoji,
+            kana_fold,
+            char_word,
+            seed,
+            hasher: ShingleHasher::parse(hasher)?,
+            limits: TextLimits::new(max_text_bytes, max_word_len, max_char_run)?,
+        };
+        // Stopwords go through the same normalization as the text, so "The"
+        // still matches with lowercase on.
+        if let Some(words) = stopwords {
+            let mut normalized = FnvHashSet::default();
+            for w in words {
+                let w = config.normalize(w)?;
+                if !w.trim().is_empty() {
+                    normalized.insert(w.trim().to_string());
+                }
+            }
+            config.stopwords = Some(normalized);
+        }
+        config.preprocessor = preprocessor.map(Preprocessor::new).transpose()?;
+        Ok(SuperMinHasher {
+            minhash,
+            size,
+            precision,
+            config,
+            distinct: track_distinct.then(|| DistinctCounts {
+                shingles: HyperLogLog::new(14),
+                documents: HyperLogLog::new(14),
+            }),
+            shingle_count: 0,
+            imported: None,
+            cache: cache_size.map(SignatureCache::new),
+        })
+    }
+
+    /// Stable identifier of every setting that affects signature values.
+    /// Signatures are only comparable between hashers with equal fingerprints.
+    pub(crate) fn fingerprint(&self) -> u64 {
+        let mut h = FnvHasher::default();
+        h.write(b"superminhash-v1");
+        h.write_u64(self.size as u64);
+        let config = &self.config;
+        h.write_u64(config.n_gram as u64);
+        for flag in [
+            config.lowercase,
+            config.unicode_normalize,
+            config.zh_variant.is_some(),
+            config.punct_norm,
+        ] {
+            h.write_u8(flag as u8);
+        }
+        if config.zh_variant.is_some_and(|v| v != Variant::ZhHans) {
+            h.write(zh_variant_name(config.zh_variant).as_bytes());
+        }
+        if let Some(re) = &config.punct_re {
+            h.write(re.as_str().as_bytes());
+        }
+        if config.short_text != ShortText::Whole {
+            h.write(config.short_text.name().as_bytes());
+        }
+        if config.anchor_ends {
+            h.write(b"anchor_ends");
+        }
+        if let Some(re) = &config.keep_re {
+            h.write(b"keep_chars");
+            h.write(re.as_str().as_bytes());
+        }
+        if config.words {
+            h.write(b"token_mode=word");
+        }
+        if let Some(cw) = &config.char_word {
+            h.write(b"token_mode=char+word");
+            h.write_u64(cw.n_gram as u64);
+            h.write_u32(cw.weight);
+        }
+        if config.strip_accents {
+            h.write(b"strip_accents");
+        }
+        if let Some(stopwords) = &config.stopwords {
+            h.write(b"stopwords");
+            let mut sorted: Vec<&String> = stopwords.iter().collect();
+            sorted.sort();
+            for w in sorted {
+                h.write(w.as_bytes());
+                h.write_u8(0);
+            }
+        }
+        if config.strip_html {
+            h.write(b"strip_html");
+        }
+        if config.mask_urls {
+            h.write(b"mask_urls");
+        }
+        if config.mask_emails {
+            h.write(b"mask_emails");
+        }
+        if config.strip_emoji {
+            h.write(b"strip_emoji");
+        }
+        if config.kana_fold {
+            h.write(b"kana_fold");
+        }
+        if let Some(preprocessor) = &config.preprocessor {
+            h.write(b"preprocessor");
+            h.write(preprocessor.name.as_bytes());
+        }
+        if let Some(seed) = config.seed {
+            h.write(b"seed");
+            h.write_u64(seed);
+        }
+        if config.hasher != ShingleHasher::Fnv {
+            h.write(b"hasher=");
+            h.write(config.hasher.name().as_bytes());
+        }
+        if let Some(max) = config.limits.max_word_len {
+            h.write(b"max_word_len");
+            h.write_u64(max as u64);
+        }
+        if let Some(max) = config.limits.max_char_run {
+            h.write(b"max_char_run");
+            h.write_u64(max as u64);
+        }
+        if self.precision != Precision::F32 {
+            h.write(b"precision=");
+            h.write(self.precision.name().as_bytes());
+        }
+        h.finish()
+    }
+
+    #[inline]
+    fn sketch(&mut self, s: String) -> PyResult<()> {
+        let minhash = &mut self.minhash;
+        let count = &mut self.shingle_count;
+        let mut hll = self.distinct.as_mut().map(|d| &mut d.shingles);
+        self.config.for_each_shingle(s, |h| {
+            if let Some(hll) = &mut hll {
+                hll.insert(h);
+            }
+            *count += 1;
+            minhash.sketch(h);
+        })
+    }
+
+    /// Returns the signature and resets for the next document. With `take`,
+    /// only its first `take` slots: prefixes of equal length are comparable
+    /// like signatures of that size, so one pass can feed both a short
+    /// signature for LSH and the full one for verification. Slots are floats
+    /// of the configured `precision`; `dtype` "u32" or "u64" returns each as
+    /// the integer bit pattern of its float instead, see `SignatureDtype`.
+    #[inline]
+    #[pyo3(signature = (take = None, dtype = None))]
+    fn finalize(
+        &mut self,
+        py: Python<'_>,
+        take: Option<usize>,
+        dtype: Option<&str>,
+    ) -> PyResult<PyObject> {
+        check_take(self.size, take)?;
+        let dtype = dtype.map(SignatureDtype::parse).transpose()?;
+        SignatureDtype::convert(dtype, py, truncate_signature(self.finish(), take)?)
+    }
+
+    /// The signature `finalize` would return now, without resetting, so a
+    /// growing document can be watched as it is sketched. Distinct counts
+    /// only record finalized signatures.
+    #[pyo3(signature = (take = None, dtype = None))]
+    fn peek(&self, py: Python<'_>, take: Option<usize>, dtype: Option<&str>) -> PyResult<PyObject> {
+        check_take(self.size, take)?;
+        let dtype = dtype.map(SignatureDtype::parse).transpose()?;
+        SignatureDtype::convert(dtype, py, truncate_signature(self.current(), take)?)
+    }
+
+    /// Estimated number of distinct shingles in the current sketch, read off
+    /// its slot values, so repeated shingles count once and no extra state
+    /// is kept. The relative error is about 1/sqrt(size) or better; 0 before
+    /// anything was sketched.
+    fn estimated_cardinality(&self) -> f64 {
+        self.current().estimated_cardinality()
+    }
+
+    /// Adds the text of the UTF-8 file at `path` to the current sketch, like
+    /// `sketch` of its whole contents but reading `chunk_size` bytes at a
+    /// time without holding the GIL. A read or decoding error, or passing
+    /// `max_text_bytes`, can leave part of the file in the sketch. A
+    /// `preprocessor` is called on each piece rather than on the whole text.
+    #[pyo3(signature = (path, chunk_size = 1 << 20))]
+    fn sketch_file(&mut self, py: Python<'_>, path: PathBuf, chunk_size: usize) -> PyResult<()> {
+        if chunk_size == 0 {
+            return Err(PyValueError::new_err("chunk_size must be greater than 0"));
+        }
+        let file = std::fs::File::open(path)?;
+        let config = &self.config;
+        let minhash = &mut self.minhash;
+        let count = &mut self.shingle_count;
+        let mut hll = self.distinct.as_mut().map(|d| &mut d.shingles);
+        py.allow_threads(|| {
+            config.for_each_shingle_read(file, chunk_size, |h| {
+                if let Some(hll) = &mut hll {
+                    hll.insert(h);
+                }
+                *count += 1;
+                minhash.sketch(h);
+            })
+        })
+    }
+
+    /// Adds the text of the strings `chunks` yields to the current sketch,
+    /// like `sketch` of their concatenation: shingles spanning chunk
+    /// boundaries are kept, as in `sketch_file`, and nothing is joined in
+    /// memory. Text limits apply to the concatenation. An error raised by
+    /// the iterator, or passing `max_text_bytes`, leaves the chunks before
+    /// it in the sketch.
+    fn sketch_iter(&mut self, chunks: &Bound<'_, PyAny>) -> PyResult<()> {
+        let mut reader = PyChunks {
+            iter: chunks.try_iter()?,
+            pending: Vec::new(),
+            pos: 0,
+            error: None,
+        };
+        let config = &self.config;
+        let minhash = &mut self.minhash;
+        let count = &mut self.shingle_count;
+        let mut hll = self.distinct.as_mut().map(|d| &mut d.shingles);
+        let sketched = config.for_each_shingle_read(&mut reader, ITER_READ_SIZE, |h| {
+            if let Some(hll) = &mut hll {
+                hll.insert(h);
+            }
+            *count += 1;
+            minhash.sketch(h);
+        });
+        match reader.error {
+            Some(e) => Err(e),
+            None => sketched,
+        }
+    }
+
+    /// Adds `n_gram`-byte shingles of raw `data` to the current sketch, like
+    /// `sketch` but with no normalization, word splitting or sentinels.
+    /// `short_text` applies to data shorter than `n_gram` bytes.
+    fn sketch_bytes(&mut self, data: &[u8]) -> PyResult<()> {
+        let config = &self.config;
+        config.limits.check_len(data.len())?;
+        if data.len() < config.n_gram && config.short_text == ShortText::Raise {
+            return Err(PyValueError::new_err(format!(
+                "data has {} bytes, fewer than n_gram={}",
+                data.len(),
+                config.n_gram
+            )));
+        }
+        let mut hashes = Vec::new();
+        config.for_each_window(data, 0, |_, shingle| {
+            hashes.push(config.seeded(config.hasher.hash(shingle)))
+        })?;
+        self.sketch_hashes(hashes);
+        Ok(())
+    }
+
+    /// The unfinished sketch as bytes, for finishing a document elsewhere:
+    /// another hasher with the same fingerprint merges it with
+    /// `import_state` and keeps sketching. The sketch itself is unchanged.
+    fn export_state<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        let values = self.current().into_f32()?;
+        let state = crate::partial::PartialSketch {
+            fingerprint: self.fingerprint(),
+            shingle_count: self.shingle_count as u64,
+            values,
+        };
+        Ok(PyBytes::new(py, &crate::partial::encode(&state)))
+    }
+
+    /// Merges a state from `export_state` into the current sketch, as if its
+    /// shingles had been fed here. Distinct counts are not transferred.
+    fn import_state(&mut self, data: &[u8]) -> PyResult<()> {
+        let state = crate::partial::decode(data)?;
+        if state.fingerprint != self.fingerprint() {
+            return Err(PyValueError::new_err(format!(
+                "sketch state fingerprint {:#x} does not match this hasher's {:#x}",
+                state.fingerprint,
+                self.fingerprint()
+            )));
+        }
+        match &mut self.imported {
+            Some(imported) => crate::partial::merge_into(imported, &state.values),
+            None => self.imported = Some(state.values),
+        }
+        self.shingle_count += state.shingle_count as usize;
+        Ok(())
+    }
+
+    /// What the pipeline hashes for `s`: the normalized text, the
+    /// `short_text` policy if it applied, its shingles in order and the
+    /// resulting signature. Leaves the sketch state and
+    /// distinct counts untouched.
+    fn explain_sketch<'py>(&self, py: Python<'py>, s: String) -> PyResult<Bound<'py, PyDict>> {
+        let normalized = self.config.normalize(s.clone())?;
+        let shingles = self.config.shingle_strings(&normalized)?;
+        let mut minhash = self.new_sketcher();
+        self.config.for_each_shingle(s, |h| minhash.sketch(h))?;
+        let config = &self.config;
+        let units = if config.words {
+            config.shingle_words(&normalized).len()
+        } else {
+            config.shingle_chars(&normalized).len()
+        };
+        let short_text = (units < config.n_gram).then(|| config.short_text.name());
+        let dict = PyDict::new(py);
+        dict.set_item("normalized", normalized)?;
+        dict.set_item("short_text", short_text)?;
+        dict.set_item("shingles", shingles)?;
+        dict.set_item("signature", minhash.signature().into_py(py)?)?;
+        Ok(dict)
+    }
+
+    /// `s` after the configured normalization, exactly as `sketch` shingles
+    /// it.
+    fn normalize(&self, s: String) -> PyResult<String> {
+        self.config.normalize(s)
+    }
+
+    /// `max_text_bytes`, `max_word_len` and `max_char_run`, `None` where
+    /// unlimited. They apply to the whole of a `sketch_file` or
+    /// `sketch_iter` text across its chunks, as to one `sketch` text.
+    fn limits(&self) -> FnvHashMap<String, Option<usize>> {
+        self.config.limits.to_dict()
+    }
+
+    /// Estimated number of distinct shingles and of distinct finalized
+    /// signatures (exact duplicate documents count once) seen since
+    /// construction or the last `reset_distinct()`. Requires
+    /// `track_distinct=True`.
+    fn distinct_counts(&self) -> PyResult<FnvHashMap<String, f64>> {
+        let distinct = self.distinct.as_ref().ok_or_else(|| {
+            PyValueError::new_err("distinct counting requires track_distinct=True")
+        })?;
+        let mut counts = FnvHashMap::default();
+        counts.insert("shingles".to_string(), distinct.shingles.estimate());
+        counts.insert("documents".to_string(), distinct.documents.estimate());
+        Ok(counts)
+    }
+
+    /// Hits, misses and current and maximum size of the signature cache.
+    /// Only one-shot sketches use it, as in `SuperMinHasherLSH.check_and_add`
+    /// or `StreamingDeduper`, not `sketch()`/`finalize()`. Requires
+    /// `cache_size`.
+    fn cache_stats(&self) -> PyResult<FnvHashMap<String, u64>> {
+        let cache = self
+            .cache
+            .as_ref()
+            .ok_or_else(|| PyValueError::new_err("cache_stats requires cache_size"))?;
+        let mut stats = FnvHashMap::default();
+        stats.insert("hits".to_string(), cache.hits);
+        stats.insert("misses".to_string(), cache.misses);
+        stats.insert("size".to_string(), cache.len() as u64);
+        stats.insert("capacity".to_string(), cache.capacity() as u64);
+        Ok(stats)
+    }
+
+    /// See `sampling::estimate_dup_rate`.
+    #[pyo3(signature = (texts, sample_size, threshold = 0.5, seed = None))]
+    fn estimate_dup_rate(
+        &self,
+        texts: &Bound<'_, PyAny>,
+        sample_size: usize,
+        threshold: f64,
+        seed: Option<u64>,
+    ) -> PyResult<FnvHashMap<String, f64>> {
+        crate::sampling::estimate_dup_rate(self, texts, sample_size, threshold, seed)
+    }
+
+    /// See `sampling::corpus_overlap`.
+    #[pyo3(signature = (a, b, threshold = 0.5, examples = 10, seed = None))]
+    fn corpus_overlap<'py>(
+        &self,
+        a: &Bound<'py, PyAny>,
+        b: &Bound<'py, PyAny>,
+        threshold: f64,
+        examples: usize,
+        seed: Option<u64>,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        crate::sampling::corpus_overlap(self, a, b, threshold, examples, seed)
+    }
+
+    /// See `sampling::find_contaminated`.
+    #[pyo3(signature = (benchmark, corpus, threshold = 0.5, batch_size = 4096))]
+    fn find_contaminated(
+        &mut self,
+        py: Python<'_>,
+        benchmark: Vec<String>,
+        corpus: &Bound<'_, PyAny>,
+        threshold: f64,
+        batch_size: usize,
+    ) -> PyResult<Vec<(String, usize, f64)>> {
+        crate::sampling::find_contaminated(py, self, benchmark, corpus, threshold, batch_size)
+    }
+
+    /// Groups `texts` into near-duplicate clusters (transitively, at
+    /// `threshold`). Returns one label per text: the position of the first
+    /// text of its cluster. A text `short_text="skip"` leaves out is a
+    /// cluster of its own. Like `dedup_texts`, each text is sketched on its
+    /// own in parallel with the GIL released, leaving the current sketch as
+    /// it is.
+    #[pyo3(signature = (texts, threshold = 0.5))]
+    fn cluster_texts(
+        &mut self,
+        py: Python<'_>,
+        texts: Vec<String>,
+        threshold: f64,
+    ) -> PyResult<Vec<usize>> {
+        let mut labels: Vec<usize> = (0..texts.len()).collect();
+        let signatures = py.allow_threads(|| self.sketch_parallel(texts))?;
+        let mut lsh = LSH::new(None);
+        let mut linked = Vec::new();
+        for (i, (signature, count)) in signatures.into_iter().enumerate() {
+            if !self.skips(count) {
+                lsh.add_linked(&i.to_string(), signature, threshold)?;
+                linked.push(i);
+            }
+        }
+        for (n, &i) in linked.iter().enumerate() {
+            labels[i] = linked[lsh.cluster_label(n)];
+        }
+        Ok(labels)
+    }
+
+    /// Deduplicates `texts` greedily and returns the indices of the texts
+    /// kept, in ascending order. With `keep="first"` texts are visited in
+    /// input order, with `keep="longest"` from longest to shortest, and each
+    /// is kept unless it matches an already kept text at `threshold`. Texts
+    /// `short_text="skip"` leaves out are kept without being compared.
+    /// Sketching runs in parallel with the GIL released.
+    #[pyo3(signature = (texts, threshold = 0.5, keep = "first"))]
+    fn dedup_texts(
+        &mut self,
+        py: Python<'_>,
+        texts: Vec<String>,
+        threshold: f64,
+        keep: &str,
+    ) -> PyResult<Vec<usize>> {
+        let mut order: Vec<usize> = (0..texts.len()).collect();
+        match keep {
+            "first" => {}
+            "longest" => {
+                let lengths: Vec<usize> = texts.iter().map(|t| t.chars().count()).collect();
+                order.sort_by_key(|&i| std::cmp::Reverse(lengths[i]));
+            }
+            _ => {
+                return Err(PyValueError::new_err(
+                    "keep must be \"first\" or \"longest\"",
+                ))
+            }
+        }
+        let signatures = py.allow_threads(|| self.sketch_parallel(texts))?;
+        let mut lsh = LSH::new(None);
+        let mut kept = Vec::new();
+        for i in order {
+            let (signature, count) = &signatures[i];
+            if self.skips(*count) {
+                kept.push(i);
+            } else if lsh.similar(signature, threshold)?.is_empty() {
+                lsh.add_signature(&i.to_string(), signature.clone())?;
+                kept.push(i);
+            }
+        }
+        kept.sort_unstable();
+        Ok(kept)
+    }
+
+    /// Signatures of every text, as if each were sketched and finalized on
+    /// its own, computed in parallel with the GIL released. The current
+    /// sketch is left as it is. `take` and `dtype` apply to each signature
+    /// as in `finalize`.
+    #[pyo3(signature = (texts, take = None, dtype = None))]
+    fn sketch_many(
+        &mut self,
+        py: Python<'_>,
+        texts: Vec<String>,
+        take: Option<usize>,
+        dtype: Option<&str>,
+    ) -> PyResult<Vec<PyObject>> {
+        check_take(self.size, take)?;
+        let dtype = dtype.map(SignatureDtype::parse).transpose()?;
+        let signatures = py.allow_threads(|| self.sketch_parallel_signatures(texts))?;
+        signatures
+            .into_iter()
+            .map(|(signature, _)| {
+                SignatureDtype::convert(dtype, py, truncate_signature(signature, take)?)
+            })
+            .collect()
+    }
+
+    fn reset_distinct(&mut self) {
+        if let Some(distinct) = &mut self.distinct {
+            distinct.shingles.clear();
+            distinct.documents.clear();
+        }
+    }
+
+    /// Starts a sketch of one document that is independent of the hasher's
+    /// own `sketch()`/`finalize()` state and of any other session.
+    fn start(slf: &Bound<'_, Self>) -> SketchSession {
+        SketchSession {
+            minhash: Some(slf.borrow().new_sketcher()),
+            hasher: slf.clone().unbind(),
+            shingle_count: 0,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn sketch_and_finalize(&mut self, s: String) -> PyResult<Vec<f32>> {
+        Ok(self.sketch_counted(s)?.0)
+    }
+
+    /// Sketches `s` as a whole document, returning its signature and shingle
+    /// count. Goes through the signature cache when the sketch is empty.
+    pub(crate) fn sketch_counted(&mut self, s: String) -> PyResult<(Vec<f32>, usize)> {
+        let empty = self.shingle_count == 0 && self.imported.is_none();
+        let key = match &mut self.cache {
+            Some(cache) if empty => {
+                let key = SignatureCache::key(&s);
+                if let Some((signature, count)) = cache.get(key) {
+                    if let Some(distinct) = &mut self.distinct {
+                        distinct.documents.insert(signature_hash(&signature));
+                    }
+                    return Ok((signature, count));
+                }
+                Some(key)
+            }
+            _ => None,
+        };
+        self.sketch(s)?;
+        let (signature, count) = self.finalize_counted()?;
+        if let (Some(key), Some(cache)) = (key, &mut self.cache) {
+            cache.insert(key, &signature, count);
+        }
+        Ok((signature, count))
+    }
+}
+
+/// Most text the chunked reader holds back waiting for a separator before
+/// cutting anyway, so a file without one is still read in bounded memory.
+const MAX_READ_CARRY: usize = 1 << 22;
+
+/// Bytes `sketch_iter` hands to the chunked reader at a time.
+const ITER_READ_SIZE: usize = 1 << 16;
+
+/// Reads the UTF-8 bytes of the strings a Python iterator yields. A Python
+/// error ends the input and is kept in `error`, to be raised in place of
+/// the I/O error it turns into.
+struct PyChunks<'py> {
+    iter: Bound<'py, PyIterator>,
+    pending: Vec<u8>,
+    pos: usize,
+    error: Option<PyErr>,
+}
+
+impl Read for PyChunks<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.pending.len() {
+            let chunk = match self.iter.next() {
+                None => return Ok(0),
+                Some(item) => item.and_then(|item| item.extract::<String>()),
+            };
+            match chunk {
+                Ok(chunk) => {
+                    self.pending = chunk.into_bytes();
+                    self.pos = 0;
+                }
+                Err(e) => {
+                    self.error = Some(e);
+                    return Err(std::io::Error::other("chunk iterator failed"));
+                }
+            }
+        }
+        let n = buf.len().min(self.pending.len() - self.pos);
+        buf[..n].copy_from_slice(&self.pending[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// One document being sketched in chunks, created by
+/// `SuperMinHasher.start()`. Each `update` shingles its chunk on its own,
+/// exactly like a `sketch()` call. Distinct counts go to the hasher.
+#[pyclass]
+pub struct SketchSession {
+    hasher: Py<SuperMinHasher>,
+    /// `None` once finalized.
+    minhash: Option<Sketcher>,
+    shingle_count: usize,
+}
+
+fn check_take(size: usize, take: Option<usize>) -> PyResult<()> {
+    match take {
+        Some(take) if take == 0 || take > size => Err(PyValueError::new_err(format!(
+            "take must be between 1 and the sketch size {}",
+            size
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// The first `take` slots of `signature`, all of them for `None`.
+fn truncate_signature(mut signature: Signature, take: Option<usize>) -> PyResult<Signature> {
+    check_take(signature.len(), take)?;
+    if let Some(take) = take {
+        signature.truncate(take);
+    }
+    Ok(signature)
+}
+
+/// Types `finalize` can return slots as, the float of the signature's
+/// precision by default. The integer ones are the bit patterns of the float
+/// values, the same bytes LSH bucket keys are built from; since every slot
+/// is non-negative they order and compare like the floats. "u64" widens f32
+/// patterns and is the only integer type for f64 ones.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum SignatureDtype {
+    F32,
+    F64,
+    U32,
+    U64,
+}
+
+impl SignatureDtype {
+    const NAMES: [(&'static str, SignatureDtype); 4] = [
+        ("f32", SignatureDtype::F32),
+        ("f64", SignatureDtype::F64),
+        ("u32", SignatureDtype::U32),
+        ("u64", SignatureDtype::U64),
+    ];
+
+    pub(crate) fn parse(name: &str) -> PyResult<Self> {
+        Self::NAMES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|&(_, dtype)| dtype)
+            .ok_or_else(|| PyValueError::new_err("dtype must be 'f32', 'f64', 'u32' or 'u64'"))
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        Self::NAMES.iter().find(|(_, d)| *d == self).unwrap().0
+    }
+
+    fn convert(
+        dtype: Option<SignatureDtype>,
+        py: Python<'_>,
+        signature: Signature,
+    ) -> PyResult<PyObject> {
+        match (dtype, signature) {
+            (None | Some(SignatureDtype::F32), Signature::F32(values)) => values.into_py_any(py),
+            (None | Some(SignatureDtype::F64), Signature::F64(values)) => values.into_py_any(py),
+            (Some(SignatureDtype::U32), Signature::F32(values)) => values
+                .iter()
+                .map(|x| x.to_bits())
+                .collect::<Vec<_>>()
+                .into_py_any(py),
+            (Some(SignatureDtype::U64), Signature::F32(values)) => values
+                .iter()
+                .map(|x| u64::from(x.to_bits()))
+                .collect::<Vec<_>>()
+                .into_py_any(py),
+            (Some(SignatureDtype::U64), Signature::F64(values)) => values
+                .iter()
+                .map(|x| x.to_bits())
+                .collect::<Vec<_>>()
+                .into_py_any(py),
+            (Some(dtype), signature) => Err(PyValueError::new_err(format!(
+                "dtype '{}' does not fit precision='{}' signatures",
+                dtype.name(),
+                signature.precision().name()
+            ))),
+        }
+    }
+}
+
+fn finalized_error() -> PyErr {
+    PyValueError::new_err("sketch session is already finalized")
+}
+
+#[pymethods]
+impl SketchSession {
+    fn update(&mut self, py: Python<'_>, chunk: String) -> PyResult<()> {
+        let hasher = self.hasher.clone_ref(py);
+        let mut hasher = hasher.borrow_mut(py);
+        let hasher = &mut *hasher;
+        let mut hll = hasher.distinct.as_mut().map(|d| &mut d.shingles);
+        let count = &mut self.shingle_count;
+        let minhash = self.minhash.as_mut().ok_or_else(finalized_error)?;
+        hasher.config.for_each_shingle(chunk, |h| {
+            if let Some(hll) = &mut hll {
+                hll.insert(h);
+            }
+            *count += 1;
+            minhash.sketch(h);
+        })
+    }
+
+    /// Shingles fed into the session so far.
+    fn shingle_count(&self) -> usize {
+        self.shingle_count
+    }
+
+    /// The signature of the chunks so far, like `SuperMinHasher.peek`; the
+    /// session stays open.
+    #[pyo3(signature = (take = None, dtype = None))]
+    fn peek(&self, py: Python<'_>, take: Option<usize>, dtype: Option<&str>) -> PyResult<PyObject> {
+        let dtype = dtype.map(SignatureDtype::parse).transpose()?;
+        let minhash = self.minhash.as_ref().ok_or_else(finalized_error)?;
+        check_take(minhash.len(), take)?;
+        SignatureDtype::convert(dtype, py, truncate_signature(minhash.signature(), take)?)
+    }
+
+    /// Returns the signature, cut to `take` slots like
+    /// `SuperMinHasher.finalize`, and closes the session; later calls fail.
+    #[pyo3(signature = (take = None, dtype = None))]
+    fn finalize(
+        &mut self,
+        py: Python<'_>,
+        take: Option<usize>,
+        dtype: Option<&str>,
+    ) -> PyResult<PyObject> {
+        let dtype = dtype.map(SignatureDtype::parse).transpose()?;
+        if let Some(minhash) = &self.minhash {
+            check_take(minhash.len(), take)?;
+        }
+        let minhash = self.minhash.take().ok_or_else(finalized_error)?;
+        let signature = minhash.signature();
+        if let Some(distinct) = &mut self.hasher.borrow_mut(py).distinct {
+            distinct.documents.insert(signature.hash());
+        }
+        SignatureDtype::convert(dtype, py, truncate_signature(signature, take)?)
+    }
+}
+
+#[pyclass]
+pub struct SuperMinHasherLSH {
+    lsh: LSH,
+    minhasher: SuperMinHasher,
+    /// Shingle hash -> (entry, offset) postings, kept when `localize=True`.
+    positions: Option<FnvHashMap<u64, Vec<(u32, u32)>>>,
+    affixes: Option<AffixIndex>,
+    store_text: Option<StoreText>,
+}
+
+/// Which text `store_text` keeps with each entry.
+#[derive(Clone, Copy)]
+enum StoreText {
+    Original,
+    Normalized,
 }
 
-#[pyclass]
-pub struct SuperMinHasher {
-    minhash: SuperMinHash<f32, Vec<char>, FnvHasher>,
-    n_gram: usize,
-    lowercase: bool,
-    unicode_normalize: bool,
-    zh_conv: bool,
-    punct_norm: bool,
+fn preview(text: &str, max_chars: Option<usize>) -> String {
+    match max_chars {
+        Some(n) => text.chars().take(n).collect(),
+        None => text.to_string(),
+    }
 }
 
-lazy_static! {
-    static ref ICU_NORMALIZER: icu::normalizer::ComposingNormalizer =
-        icu::normalizer::ComposingNormalizer::new_nfkc();
-    static ref SP_PUNCT_RE: regex::Regex = regex::Regex::new(r"[\s\p{Punctuation}]+").unwrap();
+/// Exact hashes of the first and last `len` normalized characters of each
+/// entry, for flagging truncated copies. Texts shorter than `len` are not
+/// recorded.
+struct AffixIndex {
+    len: usize,
+    prefixes: FnvHashMap<u64, Vec<usize>>,
+    suffixes: FnvHashMap<u64, Vec<usize>>,
 }
 
-#[pymethods]
-impl SuperMinHasher {
-    #[new]
-    #[pyo3(
-        signature = (size, n_gram = 5, lowercase = true, unicode_normalize = true, zh_conv = true, punct_norm = true),
-    )]
-    fn new(
-        size: usize,
-        n_gram: usize,
-        lowercase: bool,
-        unicode_normalize: bool,
-        zh_conv: bool,
-        punct_norm: bool,
-    ) -> PyResult<Self> {
-        if size == 0 {
-            return Err(PyValueError::new_err("size must be greater than 0"));
-        }
-        if n_gram == 0 {
-            return Err(PyValueError::new_err("n_gram must be greater than 0"));
+impl AffixIndex {
+    fn hashes(&self, normalized: &str) -> Option<(u64, u64)> {
+        let cs: Vec<char> = normalized.chars().collect();
+        if cs.len() < self.len {
+            return None;
         }
-
-        let bh = BuildHasherDefault::<FnvHasher>::default();
-        let minhash = SuperMinHash::new(size, bh);
-        Ok(SuperMinHasher {
-            minhash,
-            n_gram,
-            lowercase,
-            unicode_normalize,
-            zh_conv,
-            punct_norm,
-        })
+        Some((
+            shingle_hash(&cs[..self.len]),
+            shingle_hash(&cs[cs.len() - self.len..]),
+        ))
     }
+}
 
-    #[inline]
-    fn sketch(&mut self, mut s: String) {
-        if self.unicode_normalize {
-            s = ICU_NORMALIZER.normalize(&s);
+/// Renumbers the localize positions and affixes after `LSH::remove_entries`.
+fn remap_local(
+    positions: &mut Option<FnvHashMap<u64, Vec<(u32, u32)>>>,
+    affixes: &mut Option<AffixIndex>,
+    remap: &[Option<usize>],
+) {
+    if let Some(positions) = positions {
+        positions.retain(|_, hits| {
+            *hits = hits
+                .iter()
+                .filter_map(|&(i, pos)| remap[i as usize].map(|i| (i as u32, pos)))
+                .collect();
+            !hits.is_empty()
+        });
+    }
+    if let Some(affixes) = affixes {
+        for map in [&mut affixes.prefixes, &mut affixes.suffixes] {
+            map.retain(|_, members| {
+                *members = members.iter().filter_map(|&i| remap[i]).collect();
+                !members.is_empty()
+            });
         }
-        if self.punct_norm {
-            s = SP_PUNCT_RE.replace_all(&s, " ").to_string();
+    }
+}
+
+impl SuperMinHasherLSH {
+    /// `LSH::fragmentation_of` with the localize and affix tables.
+    fn fragmentation_of(&self) -> Fragmentation {
+        let mut report = self.lsh.fragmentation_of();
+        if let Some(positions) = &self.positions {
+            report.recoverable_bytes +=
+                table_slack::<(u64, Vec<(u32, u32)>)>(positions.capacity(), positions.len());
         }
-        if self.zh_conv {
-            s = zhconv::converters::ZH_TO_HANS_CONVERTER.convert(&s);
+        if let Some(affixes) = &self.affixes {
+            for map in [&affixes.prefixes, &affixes.suffixes] {
+                report.recoverable_bytes +=
+                    table_slack::<(u64, Vec<usize>)>(map.capacity(), map.len());
+            }
         }
-        if self.lowercase {
-            s = s.to_lowercase();
+        report
+    }
+
+    fn remove_flagged(&mut self, removed: &[bool]) -> usize {
+        let count = removed.iter().filter(|&&r| r).count();
+        if count > 0 {
+            let remap = self.lsh.remove_entries(removed);
+            remap_local(&mut self.positions, &mut self.affixes, &remap);
         }
-        let cs = s.chars().collect::<Vec<_>>();
-        if cs.len() < self.n_gram {
-            self.minhash.sketch(&cs).unwrap();
-        } else {
-            let mut current = Vec::with_capacity(self.n_gram);
-            for i in 0..cs.len() - self.n_gram + 1 {
-                current.clear();
-                current.extend_from_slice(&cs[i..i + self.n_gram]);
-                self.minhash.sketch(&current).unwrap();
+        count
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn check_and_add_text(
+        &mut self,
+        new_id: &str,
+        data: String,
+        threshold: f64,
+        add: bool,
+        add_if_dup: bool,
+        filter: &CandidateFilter,
+        mut info: EntryInfo,
+    ) -> PyResult<CheckResult> {
+        let text = self.affixes.is_some().then(|| data.clone());
+        let stored = if add { self.stored_text(&data)? } else { None };
+        let Some((signature, count, shingles)) = self.sketch_text(data)? else {
+            return Ok(CheckResult::too_short());
+        };
+        filter.set_query_shingles(count);
+        let data_bytes = to_bytes(&signature);
+        let (matches, index) = if add {
+            info.shingles = Some(count);
+            info.text = stored;
+            let (matches, index) = self
+                .lsh
+                .check_and_insert(new_id, data_bytes, threshold, add_if_dup, filter, info)?;
+            if let Some(index) = index {
+                self.record_positions(index, shingles);
+                if let Some(text) = text {
+                    self.record_affixes(index, &text)?;
+                }
             }
-        }
+            (matches, index)
+        } else {
+            (self.lsh.query(&data_bytes, threshold, filter)?, None)
+        };
+        Ok(CheckResult::new(self.lsh.to_id_map(&matches), index)
+            .with_weights(self.lsh.weights_of(&matches))
+            .with_truncated(filter.truncated.get())
+            .with_overflow(filter.overflow.get()))
     }
 
-    #[inline]
-    fn finalize(&mut self) -> Vec<f32> {
-        let s = self.minhash.get_hsketch().to_vec();
-        self.minhash.reinit();
-        s
+    /// The text to keep next to a new entry, if any.
+    fn stored_text(&self, data: &str) -> PyResult<Option<String>> {
+        Ok(match self.store_text {
+            None => None,
+            Some(StoreText::Original) => Some(data.to_string()),
+            Some(StoreText::Normalized) => Some(self.minhasher.config.normalize(data.to_string())?),
+        })
     }
 
-    #[inline]
-    fn sketch_and_finalize(&mut self, s: String) -> Vec<f32> {
-        self.sketch(s);
-        self.finalize()
+    /// Sketches `data`, also returning the shingle count and, in localize
+    /// mode, the positioned shingle hashes. `None` when `short_text="skip"`
+    /// leaves the text out.
+    #[allow(clippy::type_complexity)]
+    fn sketch_text(
+        &mut self,
+        data: String,
+    ) -> PyResult<Option<(Vec<f32>, usize, Vec<(usize, u64)>)>> {
+        let (signature, count, shingles) = if self.positions.is_some() {
+            let shingles = self.minhasher.shingles_at(data)?;
+            self.minhasher
+                .sketch_hashes(shingles.iter().map(|&(_, h)| h));
+            let (signature, count) = self.minhasher.finalize_counted()?;
+            (signature, count, shingles)
+        } else {
+            let (signature, count) = self.minhasher.sketch_counted(data)?;
+            (signature, count, Vec::new())
+        };
+        Ok((!self.minhasher.skips(count)).then_some((signature, count, shingles)))
     }
-}
 
-#[pyclass]
-pub struct SuperMinHasherLSH {
-    lsh: LSH,
-    minhasher: SuperMinHasher,
+    fn record_affixes(&mut self, index: usize, data: &str) -> PyResult<()> {
+        let Some(affixes) = &mut self.affixes else {
+            return Ok(());
+        };
+        let normalized = self.minhasher.config.normalize(data.to_string())?;
+        if let Some((prefix, suffix)) = affixes.hashes(&normalized) {
+            affixes.prefixes.entry(prefix).or_default().push(index);
+            affixes.suffixes.entry(suffix).or_default().push(index);
+        }
+        Ok(())
+    }
+
+    fn record_positions(&mut self, index: usize, shingles: Vec<(usize, u64)>) {
+        if let Some(positions) = &mut self.positions {
+            for (pos, h) in shingles {
+                positions
+                    .entry(h)
+                    .or_default()
+                    .push((index as u32, pos as u32));
+            }
+        }
+    }
 }
 
 #[pymethods]
 impl SuperMinHasherLSH {
     #[new]
     #[pyo3(
-        signature = (size, n_gram = 5, lowercase = true, unicode_normalize = true, zh_conv = true, punct_norm = true),
+        signature = (size, n_gram = 5, lowercase = true, unicode_normalize = true, zh_conv = true, punct_norm = true, track_distinct = false, localize = false, affix_len = None, bits = None, punct_pattern = None, store_text = "none", key_bytes = 4, boilerplate_fraction = None, short_text = "whole", anchor_ends = false, keep_chars = None, token_mode = "char", bucket_slots = None, strip_accents = false, zh_variant = None, stopwords = None, strip_html = false, mask_urls = false, mask_emails = false, preprocessor = None, strip_emoji = false, kana_fold = false, word_n_gram = None, word_weight = None, cache_size = None, seed = None, hasher = "fnv", max_text_bytes = None, max_word_len = None, max_char_run = None, hashed_buckets = false, max_memory_bytes = None),
     )]
+    #[allow(clippy::too_many_arguments)]
     fn new(
         size: usize,
         n_gram: usize,
@@ -242,7 +4604,52 @@ impl SuperMinHasherLSH {
         unicode_normalize: bool,
         zh_conv: bool,
         punct_norm: bool,
+        track_distinct: bool,
+        localize: bool,
+        affix_len: Option<usize>,
+        bits: Option<u32>,
+        punct_pattern: Option<String>,
+        store_text: &str,
+        key_bytes: usize,
+        boilerplate_fraction: Option<f64>,
+        short_text: &str,
+        anchor_ends: bool,
+        keep_chars: Option<String>,
+        token_mode: &str,
+        bucket_slots: Option<usize>,
+        strip_accents: bool,
+        zh_variant: Option<&str>,
+        stopwords: Option<FnvHashSet<String>>,
+        strip_html: bool,
+        mask_urls: bool,
+        mask_emails: bool,
+        preprocessor: Option<Bound<'_, PyAny>>,
+        strip_emoji: bool,
+        kana_fold: bool,
+        word_n_gram: Option<usize>,
+        word_weight: Option<u32>,
+        cache_size: Option<usize>,
+        seed: Option<u64>,
+        hasher: &str,
+        max_text_bytes: Option<usize>,
+        max_word_len: Option<usize>,
+        max_char_run: Option<usize>,
+        hashed_buckets: bool,
+        max_memory_bytes: Option<usize>,
     ) -> PyResult<Self> {
+        if affix_len == Some(0) {
+            return Err(PyValueError::new_err("affix_len must be greater than 0"));
+        }
+        let store_text = match store_text {
+            "none" => None,
+            "original" => Some(StoreText::Original),
+            "normalized" => Some(StoreText::Normalized),
+            _ => {
+                return Err(PyValueError::new_err(
+                    "store_text must be 'none', 'original' or 'normalized'",
+                ))
+            }
+        };
         let minhasher = SuperMinHasher::new(
             size,
             n_gram,
@@ -250,42 +4657,731 @@ impl SuperMinHasherLSH {
             unicode_normalize,
             zh_conv,
             punct_norm,
+            track_distinct,
+            punct_pattern,
+            short_text,
+            anchor_ends,
+            keep_chars,
+            token_mode,
+            strip_accents,
+            zh_variant,
+            stopwords,
+            strip_html,
+            mask_urls,
+            mask_emails,
+            preprocessor,
+            strip_emoji,
+            kana_fold,
+            word_n_gram,
+            word_weight,
+            cache_size,
+            seed,
+            hasher,
+            "f32",
+            max_text_bytes,
+            max_word_len,
+            max_char_run,
         )?;
-        let lsh = LSH::new();
-        Ok(SuperMinHasherLSH { lsh, minhasher })
+        if localize && minhasher.config.char_word.is_some() {
+            return Err(PyValueError::new_err(
+                "localize does not support token_mode='char+word'",
+            ));
+        }
+        let lsh = LSH::new(Some(minhasher.fingerprint()))
+            .with_bits(bits)?
+            .with_key_bytes(key_bytes)?
+            .with_hashed_buckets(hashed_buckets)
+            .with_bucket_hasher(minhasher.config.hasher)
+            .with_boilerplate_fraction(boilerplate_fraction)?
+            .with_bucket_slots(bucket_slots)?
+            .with_max_memory_bytes(max_memory_bytes)?;
+        Ok(SuperMinHasherLSH {
+            lsh,
+            minhasher,
+            positions: localize.then(FnvHashMap::default),
+            affixes: affix_len.map(|len| AffixIndex {
+                len,
+                prefixes: FnvHashMap::default(),
+                suffixes: FnvHashMap::default(),
+            }),
+            store_text,
+        })
     }
 
+    /// `rerank` is called as `rerank(data, candidate_id, similarity)` before
+    /// deciding whether to insert, so dropping every match lets the item in.
+    /// `max_results` caps the matches, `weight` counts and `language` and
+    /// `language_thresholds` apply as in `LSH.check_and_add`. With
+    /// `max_length_ratio`, entries whose shingle count is more than that
+    /// factor away from the text's are not matched. `time_budget_ms` and
+    /// `id_prefix` apply as in `LSH.check`; a text whose verification ran
+    /// out of time is not added, as in `LSH.check_and_add`.
     #[pyo3(
-        signature = (new_id, data, threshold = 0.5, add = true, add_if_dup = false),
+        signature = (new_id, data, threshold = 0.5, add = true, add_if_dup = false, metadata = None, filter = None, since = None, until = None, timestamp = None, rerank = None, max_results = None, weight = None, language = None, language_thresholds = None, max_length_ratio = None, time_budget_ms = None, id_prefix = None),
     )]
     #[inline]
+    #[allow(clippy::too_many_arguments)]
     fn check_and_add(
         &mut self,
+        py: Python<'_>,
         new_id: &str,
         data: String,
         threshold: f64,
         add: bool,
         add_if_dup: bool,
-    ) -> FnvHashMap<String, f64> {
-        self.minhasher.sketch(data);
-        let result = if add {
-            self.lsh
-                .check_and_add(new_id, self.minhasher.finalize(), threshold, add_if_dup)
-        } else {
-            self.lsh.check(self.minhasher.finalize(), threshold)
+        metadata: Option<FnvHashMap<String, String>>,
+        filter: Option<&Bound<'_, PyAny>>,
+        since: Option<f64>,
+        until: Option<f64>,
+        timestamp: Option<f64>,
+        rerank: Option<&Bound<'_, PyAny>>,
+        max_results: Option<usize>,
+        weight: Option<f64>,
+        language: Option<String>,
+        language_thresholds: Option<FnvHashMap<String, f64>>,
+        max_length_ratio: Option<f64>,
+        time_budget_ms: Option<f64>,
+        id_prefix: Option<String>,
+    ) -> PyResult<CheckResult> {
+        let filter = CandidateFilter::new(filter, since, until)?
+            .with_rerank(rerank, || Ok(data.as_str().into_pyobject(py)?.into_any()))?
+            .with_time_budget(time_budget_ms)?
+            .with_id_prefix(id_prefix)
+            .with_max_results(max_results)
+            .with_language_thresholds(language_thresholds)
+            .with_max_length_ratio(max_length_ratio, None)?;
+        let info = EntryInfo::new(metadata, timestamp)
+            .with_weight(weight)?
+            .with_language(language);
+        self.check_and_add_text(new_id, data, threshold, add, add_if_dup, &filter, info)
+    }
+
+    /// Indexes `(id, text)` pairs, sketching every text in parallel and then
+    /// inserting in input order, so the result does not depend on thread
+    /// scheduling. Without `threshold` every item is inserted; with it, items
+    /// matching an already inserted one are skipped as in `check_and_add`,
+    /// as are texts `short_text="skip"` leaves out. Returns the number of
+    /// inserted items.
+    #[pyo3(signature = (items, threshold = None))]
+    fn build(
+        &mut self,
+        py: Python<'_>,
+        items: Vec<(String, String)>,
+        threshold: Option<f64>,
+    ) -> PyResult<usize> {
+        let (ids, texts): (Vec<String>, Vec<String>) = items.into_iter().unzip();
+        let keep_texts =
+            self.positions.is_some() || self.affixes.is_some() || self.store_text.is_some();
+        let kept = keep_texts.then(|| texts.clone());
+        let minhasher = &mut self.minhasher;
+        let sketches = py.allow_threads(|| minhasher.sketch_parallel(texts))?;
+        let filter = CandidateFilter::default();
+        let mut inserted = 0;
+        for (n, (id, (signature, count))) in ids.iter().zip(sketches).enumerate() {
+            if self.minhasher.skips(count) {
+                continue;
+            }
+            let text = kept.as_ref().map(|texts| texts[n].as_str());
+            let mut info = EntryInfo::new(None, None);
+            info.shingles = Some(count);
+            info.text = match text {
+                Some(t) => self.stored_text(t)?,
+                None => None,
+            };
+            let data_bytes = to_bytes(&signature);
+            let index = match threshold {
+                Some(threshold) => {
+                    self.lsh
+                        .check_and_insert(id, data_bytes, threshold, false, &filter, info)?
+                        .1
+                }
+                None => Some(self.lsh.insert(id, data_bytes, info)?),
+            };
+            let Some(index) = index else {
+                continue;
+            };
+            inserted += 1;
+            if let Some(text) = text {
+                if self.positions.is_some() {
+                    let shingles = self.minhasher.shingles_at(text.to_string())?;
+                    self.record_positions(index, shingles);
+                }
+                self.record_affixes(index, text)?;
+            }
+        }
+        Ok(inserted)
+    }
+
+    /// Runs `check_and_add` over `(id, text)` pairs in order and returns one
+    /// result per pair. An item that fails gets a result with `error` set
+    /// instead of aborting the rest of the batch.
+    #[pyo3(signature = (items, threshold = 0.5, add = true, add_if_dup = false))]
+    fn check_and_add_many(
+        &mut self,
+        items: Vec<(String, String)>,
+        threshold: f64,
+        add: bool,
+        add_if_dup: bool,
+    ) -> Vec<CheckResult> {
+        let filter = CandidateFilter::default();
+        items
+            .into_iter()
+            .map(|(id, data)| {
+                let info = EntryInfo::new(None, None);
+                self.check_and_add_text(&id, data, threshold, add, add_if_dup, &filter, info)
+                    .unwrap_or_else(|e| CheckResult::failed(e.to_string()))
+            })
+            .collect()
+    }
+
+    /// Like `check_and_add(..., add=False)`, but each match also carries the
+    /// estimated number of shingles both texts share, derived from the
+    /// Jaccard estimate and the distinct shingle counts estimated from the
+    /// signatures: J * (|A| + |B|) / (1 + J), at most min(|A|, |B|). Repeated
+    /// shingles count once. The overlap is `None` for entries added without
+    /// a shingle count.
+    #[pyo3(signature = (data, threshold = 0.5))]
+    fn check_with_overlap(
+        &mut self,
+        data: String,
+        threshold: f64,
+    ) -> PyResult<FnvHashMap<String, (f64, Option<f64>)>> {
+        let Some((signature, _, _)) = self.sketch_text(data)? else {
+            return Ok(FnvHashMap::default());
+        };
+        let size = Signature::F32(signature.clone()).estimated_cardinality();
+        let matches = self.lsh.query(
+            &to_bytes(&signature),
+            threshold,
+            &CandidateFilter::default(),
+        )?;
+        Ok(matches
+            .into_iter()
+            .map(|(i, j)| {
+                let shared = self.lsh.entries[i]
+                    .distinct
+                    .map(|other| (j * (size + other) / (1.0 + j)).min(size.min(other)));
+                (self.lsh.ids[i].clone(), (j, shared))
+            })
+            .collect())
+    }
+
+    /// Like `check_and_add(..., add=False)`, but each match also carries its
+    /// stored text (see `store_text`), cut to `max_chars` characters if given.
+    #[pyo3(signature = (data, threshold = 0.5, max_chars = None))]
+    fn check_with_text(
+        &mut self,
+        data: String,
+        threshold: f64,
+        max_chars: Option<usize>,
+    ) -> PyResult<FnvHashMap<String, (f64, Option<String>)>> {
+        let Some((signature, _, _)) = self.sketch_text(data)? else {
+            return Ok(FnvHashMap::default());
+        };
+        let matches = self.lsh.query(
+            &to_bytes(&signature),
+            threshold,
+            &CandidateFilter::default(),
+        )?;
+        Ok(matches
+            .into_iter()
+            .map(|(i, j)| {
+                let text = self.lsh.entries[i].text.as_deref();
+                (
+                    self.lsh.ids[i].clone(),
+                    (j, text.map(|t| preview(t, max_chars))),
+                )
+            })
+            .collect())
+    }
+
+    /// Number of indexed documents at least `threshold` similar to `data`,
+    /// 0 when `short_text="skip"` leaves it out.
+    #[pyo3(signature = (data, threshold = 0.5))]
+    fn count_matches(&mut self, data: String, threshold: f64) -> PyResult<usize> {
+        match self.sketch_text(data)? {
+            Some((signature, _, _)) => self.lsh.count_matches(signature, threshold),
+            None => Ok(0),
+        }
+    }
+
+    /// 1 minus the best similarity of `data` to an indexed document, `None`
+    /// when `short_text="skip"` leaves it out.
+    fn novelty(&mut self, data: String) -> PyResult<Option<f64>> {
+        self.sketch_text(data)?
+            .map(|(signature, _, _)| self.lsh.novelty(signature))
+            .transpose()
+    }
+
+    /// The stored text of `id`, cut to `max_chars` characters if given.
+    /// `None` for unknown ids and when the index does not store text.
+    #[pyo3(signature = (id, max_chars = None))]
+    fn get_text(&self, id: &str, max_chars: Option<usize>) -> Option<String> {
+        let &i = self.lsh.id_map.get(id)?;
+        let text = self.lsh.entries[i].text.as_deref()?;
+        Some(preview(text, max_chars))
+    }
+
+    /// Indexes `data` under the next auto id and returns it, or `None` when
+    /// `short_text="skip"` leaves the text out.
+    #[pyo3(signature = (data, metadata = None, timestamp = None))]
+    fn add_auto(
+        &mut self,
+        data: String,
+        metadata: Option<FnvHashMap<String, String>>,
+        timestamp: Option<f64>,
+    ) -> PyResult<Option<String>> {
+        let text = self.affixes.is_some().then(|| data.clone());
+        let stored = self.stored_text(&data)?;
+        let Some((signature, count, shingles)) = self.sketch_text(data)? else {
+            return Ok(None);
         };
-        self.minhasher.minhash.reinit();
-        result
+        let data_bytes = to_bytes(&signature);
+        self.lsh.check_width(data_bytes.len(), true)?;
+        let new_id = self.lsh.next_id();
+        let mut info = EntryInfo::new(metadata, timestamp);
+        info.shingles = Some(count);
+        info.text = stored;
+        let index = self.lsh.insert(&new_id, data_bytes, info)?;
+        self.record_positions(index, shingles);
+        if let Some(text) = text {
+            self.record_affixes(index, &text)?;
+        }
+        Ok(Some(new_id))
+    }
+
+    /// Ids of indexed entries whose normalized text starts ("prefix") or ends
+    /// ("suffix") with exactly the same `affix_len` characters as `data`.
+    /// Requires `affix_len` to be set.
+    fn affix_duplicates(&self, data: String) -> PyResult<FnvHashMap<String, Vec<String>>> {
+        let affixes = self.affixes.as_ref().ok_or_else(|| {
+            PyValueError::new_err("affix_duplicates requires an index built with affix_len")
+        })?;
+        let mut result = FnvHashMap::default();
+        let (mut prefix_ids, mut suffix_ids) = (Vec::new(), Vec::new());
+        let normalized = self.minhasher.config.normalize(data)?;
+        if let Some((prefix, suffix)) = affixes.hashes(&normalized) {
+            for &i in affixes.prefixes.get(&prefix).into_iter().flatten() {
+                prefix_ids.push(self.lsh.ids[i].clone());
+            }
+            for &i in affixes.suffixes.get(&suffix).into_iter().flatten() {
+                suffix_ids.push(self.lsh.ids[i].clone());
+            }
+        }
+        result.insert("prefix".to_string(), prefix_ids);
+        result.insert("suffix".to_string(), suffix_ids);
+        Ok(result)
+    }
+
+    /// Finds the passages `data` shares with the indexed entry `id`, as
+    /// `(start, end, other_start, other_end)` character (or, in word mode,
+    /// word) ranges in the normalized texts, ordered by `start`. Requires
+    /// `localize=True`. With `anchor_ends`, a range ending at the end of a
+    /// text may extend one character past it.
+    fn locate(&self, data: String, id: &str) -> PyResult<Vec<(usize, usize, usize, usize)>> {
+        let positions = self.positions.as_ref().ok_or_else(|| {
+            PyValueError::new_err("locate requires an index built with localize=True")
+        })?;
+        let index = *self
+            .lsh
+            .id_map
+            .get(id)
+            .ok_or_else(|| PyValueError::new_err(format!("unknown id {:?}", id)))?;
+        let mut hits: Vec<(i64, usize, usize)> = Vec::new();
+        for (pos, h) in self.minhasher.shingles_at(data)? {
+            for &(entry, other) in positions.get(&h).into_iter().flatten() {
+                if entry as usize == index {
+                    let other = other as usize;
+                    hits.push((other as i64 - pos as i64, pos, other));
+                }
+            }
+        }
+        // Shingles that line up on the same diagonal at consecutive offsets
+        // form one copied passage.
+        hits.sort_unstable();
+        let n_gram = self.minhasher.config.n_gram;
+        let mut spans: Vec<(usize, usize, usize, usize)> = Vec::new();
+        let mut last: Option<(i64, usize)> = None;
+        for (diagonal, pos, other) in hits {
+            match (last, spans.last_mut()) {
+                (Some((d, p)), Some(span)) if d == diagonal && pos == p + 1 => {
+                    span.1 = pos + n_gram;
+                    span.3 = other + n_gram;
+                }
+                _ => spans.push((pos, pos + n_gram, other, other + n_gram)),
+            }
+            last = Some((diagonal, pos));
+        }
+        if self.minhasher.config.anchor_ends {
+            // Offsets count the begin sentinel.
+            for span in &mut spans {
+                *span = (
+                    span.0.saturating_sub(1),
+                    span.1 - 1,
+                    span.2.saturating_sub(1),
+                    span.3 - 1,
+                );
+            }
+        }
+        spans.sort_unstable();
+        Ok(spans)
     }
     fn keys(&self) -> Vec<String> {
         self.lsh.keys()
     }
 
-    fn values(&self) -> Vec<Vec<f32>> {
+    /// A read-only snapshot of the index, see `LSH.reader`. It is queried
+    /// with signatures, such as those of a `HasherPool` built with the same
+    /// options.
+    fn reader(&self) -> LSHReader {
+        self.lsh.reader()
+    }
+
+    fn values(&self) -> PyResult<Vec<Vec<f32>>> {
         self.lsh.values()
     }
 
+    fn bits(&self) -> Option<u32> {
+        self.lsh.bits()
+    }
+
+    fn sequence_of(&self, id: &str) -> Option<u64> {
+        self.lsh.sequence_of(id)
+    }
+
+    fn items(&self) -> PyResult<Vec<(u64, String, Vec<f32>)>> {
+        self.lsh.items()
+    }
+
+    fn next_sequence(&self) -> u64 {
+        self.lsh.next_sequence()
+    }
+
+    #[pyo3(signature = (sequence_no = 0))]
+    fn export_since<'py>(
+        &self,
+        py: Python<'py>,
+        sequence_no: u64,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        self.lsh.export_since(py, sequence_no)
+    }
+
+    fn apply_delta(&mut self, data: &[u8]) -> PyResult<usize> {
+        let (lsh, positions, affixes) = (&mut self.lsh, &mut self.positions, &mut self.affixes);
+        lsh.apply_delta_with(data, |remap| remap_local(positions, affixes, remap))
+    }
+
+    fn remove_many(&mut self, ids: Vec<String>) -> usize {
+        let removed = self.lsh.flag_ids(&ids);
+        self.remove_flagged(&removed)
+    }
+
+    fn remove_if(&mut self, filter: &Bound<'_, PyAny>) -> PyResult<usize> {
+        let removed = self.lsh.flag_matching(filter)?;
+        Ok(self.remove_flagged(&removed))
+    }
+
+    #[pyo3(signature = (keep = "newest", per_cluster = 1))]
+    fn prune(&mut self, keep: &str, per_cluster: usize) -> PyResult<usize> {
+        let removed = self.lsh.flag_pruned(keep, per_cluster)?;
+        Ok(self.remove_flagged(&removed))
+    }
+
+    fn fragmentation(&self) -> FnvHashMap<String, usize> {
+        self.fragmentation_of().into_dict()
+    }
+
+    /// `LSH.verify_integrity`, also checking that the position and affix
+    /// tables only refer to existing entries.
+    fn verify_integrity(&self) -> Vec<String> {
+        let n = self.lsh.ids.len();
+        let mut problems = self.lsh.integrity_problems();
+        if let Some(positions) = &self.positions {
+            let dangling = positions
+                .values()
+                .flatten()
+                .filter(|&&(i, _)| i as usize >= n);
+            if let Some((i, _)) = dangling.max() {
+                problems.push(format!("shingle positions refer to missing entry {}", i));
+            }
+        }
+        if let Some(affixes) = &self.affixes {
+            let tables = affixes.prefixes.values().chain(affixes.suffixes.values());
+            if let Some(i) = tables.flatten().filter(|&&i| i >= n).max() {
+                problems.push(format!("affix tables refer to missing entry {}", i));
+            }
+        }
+        problems
+    }
+
+    fn compact(&mut self) -> usize {
+        let freed = self.fragmentation_of().recoverable_bytes;
+        self.lsh.shrink();
+        if let Some(positions) = &mut self.positions {
+            positions.shrink_to_fit();
+        }
+        if let Some(affixes) = &mut self.affixes {
+            affixes.prefixes.shrink_to_fit();
+            affixes.suffixes.shrink_to_fit();
+        }
+        freed
+    }
+
+    #[pyo3(signature = (keep = "newest"))]
+    fn canonical_ids(&self, keep: &str) -> PyResult<Vec<String>> {
+        self.lsh.canonical_ids(keep)
+    }
+
+    fn enable_log(&mut self) -> PyResult<()> {
+        self.lsh.enable_log()
+    }
+
+    fn disable_log(&mut self) {
+        self.lsh.disable_log()
+    }
+
+    fn drain_log<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        self.lsh.drain_log(py)
+    }
+
+    fn enable_audit(&mut self) {
+        self.lsh.enable_audit()
+    }
+
+    fn disable_audit(&mut self) {
+        self.lsh.disable_audit()
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn audit(&self, id: &str) -> PyResult<Vec<(f64, Vec<(String, f64)>)>> {
+        self.lsh.audit(id)
+    }
+
+    fn export_audit(&self) -> PyResult<String> {
+        self.lsh.export_audit()
+    }
+
+    fn audit_count(&self) -> PyResult<usize> {
+        self.lsh.audit_count()
+    }
+
+    fn clear_audit(&mut self) -> PyResult<()> {
+        self.lsh.clear_audit()
+    }
+
+    #[pyo3(signature = (key = None, id_separator = None))]
+    fn stats_by(
+        &self,
+        key: Option<&str>,
+        id_separator: Option<&str>,
+    ) -> PyResult<FnvHashMap<Option<String>, FnvHashMap<&'static str, usize>>> {
+        self.lsh.stats_by(key, id_separator)
+    }
+
     fn length(&self) -> usize {
         self.lsh.length()
     }
+
+    /// The complete state as plain Python values and bytes: the constructor
+    /// arguments, the index, and the localize, affix and distinct-count data
+    /// when enabled. Restore with `SuperMinHasherLSH.from_dict`. A
+    /// `preprocessor` is not stored, only its qualified name, so the state
+    /// stays plain data; `from_dict` then needs the callable again.
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let text = &self.minhasher.config;
+        let config = PyDict::new(py);
+        config.set_item("size", self.minhasher.size)?;
+        config.set_item("n_gram", text.n_gram)?;
+        config.set_item("lowercase", text.lowercase)?;
+        config.set_item("unicode_normalize", text.unicode_normalize)?;
+        config.set_item("zh_conv", text.zh_variant.is_some())?;
+        config.set_item("zh_variant", zh_variant_name(text.zh_variant))?;
+        config.set_item("punct_norm", text.punct_norm)?;
+        config.set_item("track_distinct", self.minhasher.distinct.is_some())?;
+        config.set_item("localize", self.positions.is_some())?;
+        config.set_item("affix_len", self.affixes.as_ref().map(|a| a.len))?;
+        config.set_item("bits", self.lsh.bits())?;
+        config.set_item(
+            "punct_pattern",
+            text.punct_re.as_ref().map(|re| re.as_str()),
+        )?;
+        let store_text = match self.store_text {
+            None => "none",
+            Some(StoreText::Original) => "original",
+            Some(StoreText::Normalized) => "normalized",
+        };
+        config.set_item("store_text", store_text)?;
+        config.set_item("key_bytes", self.lsh.key_bytes)?;
+        config.set_item("hashed_buckets", self.lsh.hashed_buckets)?;
+        config.set_item("boilerplate_fraction", self.lsh.boilerplate_fraction)?;
+        config.set_item("bucket_slots", self.lsh.bucket_slots)?;
+        config.set_item("max_memory_bytes", self.lsh.max_memory_bytes)?;
+        config.set_item("short_text", text.short_text.name())?;
+        config.set_item("anchor_ends", text.anchor_ends)?;
+        config.set_item("keep_chars", text.keep_re.as_ref().map(|re| re.as_str()))?;
+        let token_mode = match (text.words, &text.char_word) {
+            (true, _) => "word",
+            (false, Some(_)) => "char+word",
+            (false, None) => "char",
+        };
+        config.set_item("token_mode", token_mode)?;
+        config.set_item("strip_accents", text.strip_accents)?;
+        config.set_item("stopwords", text.stopwords.clone())?;
+        config.set_item("strip_html", text.strip_html)?;
+        config.set_item("mask_urls", text.mask_urls)?;
+        config.set_item("mask_emails", text.mask_emails)?;
+        config.set_item("preprocessor", text.preprocessor.as_ref().map(|p| &p.name))?;
+        config.set_item("strip_emoji", text.strip_emoji)?;
+        config.set_item("kana_fold", text.kana_fold)?;
+        config.set_item("word_n_gram", text.char_word.as_ref().map(|cw| cw.n_gram))?;
+        config.set_item("word_weight", text.char_word.as_ref().map(|cw| cw.weight))?;
+        let cache_size = self.minhasher.cache.as_ref().map(|c| c.capacity());
+        config.set_item("cache_size", cache_size)?;
+        config.set_item("seed", text.seed)?;
+        config.set_item("hasher", text.hasher.name())?;
+        config.set_item("max_text_bytes", text.limits.max_text_bytes)?;
+        config.set_item("max_word_len", text.limits.max_word_len)?;
+        config.set_item("max_char_run", text.limits.max_char_run)?;
+        let state = PyDict::new(py);
+        state.set_item("config", config)?;
+        state.set_item("index", self.lsh.state(py)?)?;
+        state.set_item("positions", self.positions.clone())?;
+        let affixes = self
+            .affixes
+            .as_ref()
+            .map(|a| (a.prefixes.clone(), a.suffixes.clone()));
+        state.set_item("affixes", affixes)?;
+        let distinct = self.minhasher.distinct.as_ref().map(|d| {
+            (
+                PyBytes::new(py, d.shingles.registers()),
+                PyBytes::new(py, d.documents.registers()),
+            )
+        });
+        state.set_item("distinct", distinct)?;
+        Ok(state)
+    }
+
+    /// Restores a `to_dict` state. `preprocessor` must be given exactly when
+    /// the state was built with one, and must be the same function.
+    #[staticmethod]
+    #[pyo3(signature = (state, preprocessor = None))]
+    fn from_dict<'py>(
+        py: Python<'py>,
+        state: &Bound<'py, PyDict>,
+        preprocessor: Option<Bound<'py, PyAny>>,
+    ) -> PyResult<Bound<'py, Self>> {
+        let config: Bound<PyDict> = required::<Bound<PyDict>>(state, "config")?.copy()?;
+        let name: Option<String> = required(&config, "preprocessor")?;
+        match (&name, &preprocessor) {
+            (Some(name), None) => {
+                return Err(PyValueError::new_err(format!(
+                    "state was built with preprocessor {}; pass it to from_dict",
+                    name
+                )))
+            }
+            (None, Some(_)) => {
+                return Err(PyValueError::new_err(
+                    "state was built without a preprocessor",
+                ))
+            }
+            _ => {}
+        }
+        config.set_item("preprocessor", preprocessor)?;
+        let restored = py
+            .get_type::<SuperMinHasherLSH>()
+            .call((), Some(&config))?
+            .downcast_into::<SuperMinHasherLSH>()?;
+        {
+            let mut this = restored.borrow_mut();
+            let lsh = LSH::from_state(&required(state, "index")?)?;
+            if lsh.fingerprint != Some(this.minhasher.fingerprint()) {
+                return Err(PyValueError::new_err(
+                    "index state does not match the hasher configuration",
+                ));
+            }
+            this.lsh = lsh;
+            if this.positions.is_some() {
+                this.positions = Some(required(state, "positions")?);
+            }
+            if let Some(affixes) = &mut this.affixes {
+                (affixes.prefixes, affixes.suffixes) = required(state, "affixes")?;
+            }
+            if let Some(distinct) = &mut this.minhasher.distinct {
+                let (shingles, documents): (Bound<PyBytes>, Bound<PyBytes>) =
+                    required(state, "distinct")?;
+                if !distinct.shingles.load_registers(shingles.as_bytes())
+                    || !distinct.documents.load_registers(documents.as_bytes())
+                {
+                    return Err(PyValueError::new_err("invalid distinct-count registers"));
+                }
+            }
+        }
+        Ok(restored)
+    }
+
+    fn fingerprint(&self) -> Option<u64> {
+        self.lsh.fingerprint()
+    }
+
+    #[pyo3(signature = (k = 10, examples = 5))]
+    fn heavy_hitters<'py>(
+        &self,
+        py: Python<'py>,
+        k: usize,
+        examples: usize,
+    ) -> Vec<(Bound<'py, PyBytes>, usize, Vec<String>)> {
+        self.lsh.heavy_hitters(py, k, examples)
+    }
+
+    fn distinct_counts(&self) -> PyResult<FnvHashMap<String, f64>> {
+        self.minhasher.distinct_counts()
+    }
+
+    fn cache_stats(&self) -> PyResult<FnvHashMap<String, u64>> {
+        self.minhasher.cache_stats()
+    }
+
+    fn explain_sketch<'py>(&self, py: Python<'py>, s: String) -> PyResult<Bound<'py, PyDict>> {
+        self.minhasher.explain_sketch(py, s)
+    }
+
+    fn normalize(&self, s: String) -> PyResult<String> {
+        self.minhasher.normalize(s)
+    }
+
+    fn limits(&self) -> FnvHashMap<String, Option<usize>> {
+        self.minhasher.limits()
+    }
+
+    fn get_metadata(&self, id: &str) -> Option<FnvHashMap<String, String>> {
+        self.lsh.get_metadata(id)
+    }
+
+    fn get_timestamp(&self, id: &str) -> Option<f64> {
+        self.lsh.get_timestamp(id)
+    }
+
+    fn get_weight(&self, id: &str) -> Option<f64> {
+        self.lsh.get_weight(id)
+    }
+
+    fn get_language(&self, id: &str) -> Option<String> {
+        self.lsh.get_language(id)
+    }
+
+    fn cluster_of(&self, id: &str) -> Option<usize> {
+        self.lsh.cluster_of(id)
+    }
+
+    fn cluster_members(&self, label: usize) -> PyResult<Vec<String>> {
+        self.lsh.cluster_members(label)
+    }
+
+    fn cluster_weight(&self, label: usize) -> PyResult<FnvHashMap<String, f64>> {
+        self.lsh.cluster_weight(label)
+    }
+
+    fn link_pairs(&mut self, pairs: Vec<(String, String)>) -> usize {
+        self.lsh.link_pairs(pairs)
+    }
 }
@@ -0,0 +1,197 @@
+use crate::minhash::{SuperMinHasher, LSH};
+use crate::result::CheckResult;
+use fnv::FnvHashMap;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+/// How partial similarities are collapsed into one record-level score.
+/// `Custom` calls a Python function with the list of similarities and the
+/// matching list of weights and expects a float back.
+enum Aggregation {
+    Max,
+    Mean,
+    WeightedMean,
+    NoisyOr,
+    Custom(Py<PyAny>),
+}
+
+impl Aggregation {
+    fn from_py(aggregation: Option<&Bound<'_, PyAny>>) -> PyResult<Self> {
+        let Some(aggregation) = aggregation else {
+            return Ok(Aggregation::Max);
+        };
+        if aggregation.is_callable() {
+            return Ok(Aggregation::Custom(aggregation.clone().unbind()));
+        }
+        match aggregation.extract::<String>()?.as_str() {
+            "max" => Ok(Aggregation::Max),
+            "mean" => Ok(Aggregation::Mean),
+            "weighted_mean" => Ok(Aggregation::WeightedMean),
+            "noisy_or" => Ok(Aggregation::NoisyOr),
+            name => Err(PyValueError::new_err(format!(
+                "unknown aggregation {:?}, expected \"max\", \"mean\", \"weighted_mean\", \
+                 \"noisy_or\" or a callable",
+                name
+            ))),
+        }
+    }
+
+    /// `sims` and `weights` hold one entry per field present in the query;
+    /// fields the candidate did not match contribute a similarity of 0.
+    fn combine(&self, py: Python<'_>, sims: &[f64], weights: &[f64]) -> PyResult<f64> {
+        Ok(match self {
+            Aggregation::Max => sims.iter().copied().fold(0.0, f64::max),
+            Aggregation::Mean => sims.iter().sum::<f64>() / sims.len() as f64,
+            Aggregation::WeightedMean => {
+                let total: f64 = weights.iter().sum();
+                if total == 0.0 {
+                    0.0
+                } else {
+                    sims.iter().zip(weights).map(|(s, w)| s * w).sum::<f64>() / total
+                }
+            }
+            Aggregation::NoisyOr => 1.0 - sims.iter().map(|s| 1.0 - s).product::<f64>(),
+            Aggregation::Custom(f) => f
+                .call1(py, (sims.to_vec(), weights.to_vec()))?
+                .extract(py)?,
+        })
+    }
+}
+
+struct Field {
+    name: String,
+    weight: f64,
+    lsh: LSH,
+}
+
+/// Deduplicates structured records by keeping one index per field and
+/// scoring candidates on the aggregate of their per-field similarities.
+#[pyclass]
+pub struct MultiFieldLSH {
+    hasher: Py<SuperMinHasher>,
+    fields: Vec<Field>,
+    aggregation: Aggregation,
+    ids: Vec<String>,
+}
+
+#[pymethods]
+impl MultiFieldLSH {
+    /// `fields` maps each field name to its weight. `aggregation` is one of
+    /// "max" (the default), "mean", "weighted_mean", "noisy_or" or a callable
+    /// `(similarities, weights) -> float`. Remaining keyword arguments are
+    /// passed to `SuperMinHasher`.
+    #[new]
+    #[pyo3(signature = (fields, size, aggregation = None, **kwargs))]
+    fn new(
+        py: Python<'_>,
+        fields: FnvHashMap<String, f64>,
+        size: usize,
+        aggregation: Option<&Bound<'_, PyAny>>,
+        kwargs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<Self> {
+        if fields.is_empty() {
+            return Err(PyValueError::new_err("fields must not be empty"));
+        }
+        if fields.values().any(|&w| w < 0.0 || !w.is_finite()) {
+            return Err(PyValueError::new_err(
+                "field weights must be finite and non-negative",
+            ));
+        }
+        let hasher: Py<SuperMinHasher> = py
+            .get_type::<SuperMinHasher>()
+            .call((size,), kwargs)?
+            .extract()?;
+        let fingerprint = hasher.borrow(py).fingerprint();
+        let mut fields: Vec<Field> = fields
+            .into_iter()
+            .map(|(name, weight)| Field {
+                name,
+                weight,
+                lsh: LSH::new(Some(fingerprint)),
+            })
+            .collect();
+        fields.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(MultiFieldLSH {
+            hasher,
+            fields,
+            aggregation: Aggregation::from_py(aggregation)?,
+            ids: Vec::new(),
+        })
+    }
+
+    /// Scores `record` against every indexed record and, when `add` is set
+    /// and nothing reaches `threshold` (or `add_if_dup` is set), indexes it.
+    /// Fields missing from `record` are ignored for scoring, as are fields
+    /// `short_text="skip"` leaves out, which are not indexed either.
+    #[pyo3(signature = (new_id, record, threshold = 0.5, add = true, add_if_dup = false))]
+    fn check_and_add(
+        &mut self,
+        py: Python<'_>,
+        new_id: &str,
+        record: FnvHashMap<String, String>,
+        threshold: f64,
+        add: bool,
+        add_if_dup: bool,
+    ) -> PyResult<CheckResult> {
+        if let Some(unknown) = record
+            .keys()
+            .find(|k| !self.fields.iter().any(|f| &f.name == *k))
+        {
+            return Err(PyValueError::new_err(format!(
+                "unknown field {:?}",
+                unknown
+            )));
+        }
+        let mut hasher = self.hasher.borrow_mut(py);
+        let mut signatures = Vec::new();
+        let mut per_field: FnvHashMap<String, Vec<f64>> = FnvHashMap::default();
+        let mut weights = Vec::new();
+        for (pos, field) in self.fields.iter().enumerate() {
+            let Some(text) = record.get(&field.name) else {
+                continue;
+            };
+            let (signature, count) = hasher.sketch_counted(text.clone())?;
+            if hasher.skips(count) {
+                continue;
+            }
+            let slot = weights.len();
+            weights.push(field.weight);
+            for (id, similarity) in field.lsh.similar(&signature, 0.0)? {
+                let sims = per_field.entry(id).or_default();
+                sims.resize(slot, 0.0);
+                sims.push(similarity);
+            }
+            signatures.push((pos, signature));
+        }
+        let mut result = FnvHashMap::default();
+        for (id, mut sims) in per_field {
+            sims.resize(weights.len(), 0.0);
+            let score = self.aggregation.combine(py, &sims, &weights)?;
+            if score >= threshold {
+                result.insert(id, score);
+            }
+        }
+        let mut index = None;
+        if add && (result.is_empty() || add_if_dup) {
+            for (pos, signature) in signatures {
+                self.fields[pos].lsh.add_signature(new_id, signature)?;
+            }
+            index = Some(self.ids.len());
+            self.ids.push(new_id.to_string());
+        }
+        Ok(CheckResult::new(result, index))
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.ids.clone()
+    }
+
+    fn length(&self) -> usize {
+        self.ids.len()
+    }
+
+    fn fields(&self) -> Vec<String> {
+        self.fields.iter().map(|f| f.name.clone()).collect()
+    }
+}
@@ -0,0 +1,83 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+const MAGIC: &[u8; 4] = b"W6SP";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 4 + 1 + 8 + 8 + 4;
+
+/// An unfinished sketch as shipped between `export_state` and
+/// `import_state`.
+pub(crate) struct PartialSketch {
+    pub(crate) fingerprint: u64,
+    pub(crate) shingle_count: u64,
+    pub(crate) values: Vec<f32>,
+}
+
+/// Takes the slot-wise minimum with `other`. SuperMinHash values of an item
+/// do not depend on the items sketched before it, so this gives the sketch
+/// of both inputs together.
+pub(crate) fn merge_into(values: &mut [f32], other: &[f32]) {
+    for (v, &o) in values.iter_mut().zip(other) {
+        if o < *v {
+            *v = o;
+        }
+    }
+}
+
+/// Encodes a partial sketch, little-endian:
+///
+/// ```text
+/// "W6SP" version:u8 fingerprint:u64 shingle_count:u64 size:u32
+/// size * value:f32 crc:u32
+/// ```
+///
+/// where the CRC-32 covers everything before it.
+pub(crate) fn encode(sketch: &PartialSketch) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN + 4 * sketch.values.len() + 4);
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+    out.extend_from_slice(&sketch.fingerprint.to_le_bytes());
+    out.extend_from_slice(&sketch.shingle_count.to_le_bytes());
+    out.extend_from_slice(&(sketch.values.len() as u32).to_le_bytes());
+    for v in &sketch.values {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+    let crc = crc32fast::hash(&out);
+    out.extend_from_slice(&crc.to_le_bytes());
+    out
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> PyResult<PartialSketch> {
+    if bytes.len() < HEADER_LEN + 4 || &bytes[..4] != MAGIC {
+        return Err(PyValueError::new_err("not a w6sketch sketch state"));
+    }
+    if bytes[4] != VERSION {
+        return Err(PyValueError::new_err(format!(
+            "unsupported sketch state version {}",
+            bytes[4]
+        )));
+    }
+    let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+    let size = u32::from_le_bytes(bytes[21..25].try_into().unwrap()) as usize;
+    let body = HEADER_LEN + 4 * size;
+    if bytes.len() != body + 4 {
+        return Err(PyValueError::new_err(format!(
+            "sketch state has {} bytes, expected {} for {} slots",
+            bytes.len(),
+            body + 4,
+            size
+        )));
+    }
+    let expected = u32::from_le_bytes(bytes[body..].try_into().unwrap());
+    if crc32fast::hash(&bytes[..body]) != expected {
+        return Err(PyValueError::new_err("sketch state checksum mismatch"));
+    }
+    Ok(PartialSketch {
+        fingerprint: u64_at(5),
+        shingle_count: u64_at(13),
+        values: bytes[HEADER_LEN..body]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect(),
+    })
+}
@@ -0,0 +1,87 @@
+use crate::minhash::{Sketcher, SuperMinHasher};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::sync::Mutex;
+
+/// Sketches texts from any number of Python threads with one shared
+/// configuration. Each call borrows an idle sketcher (creating one when all
+/// are busy) and releases the GIL while sketching. At most `pool_size`
+/// idle sketchers are kept; ones returned beyond that are dropped. Distinct
+/// counts are not tracked.
+#[pyclass(frozen)]
+pub struct HasherPool {
+    prototype: Py<SuperMinHasher>,
+    idle: Mutex<Vec<Sketcher>>,
+    sketch_size: usize,
+    pool_size: usize,
+}
+
+#[pymethods]
+impl HasherPool {
+    /// `sketch_size` is the signature length and `kwargs` are passed to
+    /// `SuperMinHasher` with it. `pool_size` defaults to the number of CPUs.
+    #[new]
+    #[pyo3(signature = (sketch_size, pool_size = None, **kwargs))]
+    fn new(
+        py: Python<'_>,
+        sketch_size: usize,
+        pool_size: Option<usize>,
+        kwargs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<Self> {
+        let pool_size = match pool_size {
+            Some(0) => return Err(PyValueError::new_err("pool_size must be greater than 0")),
+            Some(n) => n,
+            None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+        let prototype: Py<SuperMinHasher> = py
+            .get_type::<SuperMinHasher>()
+            .call((sketch_size,), kwargs)?
+            .extract()?;
+        Ok(HasherPool {
+            prototype,
+            idle: Mutex::new(Vec::new()),
+            sketch_size,
+            pool_size,
+        })
+    }
+
+    /// The signature of `data`, or `None` for a text with no shingles when
+    /// the hasher was built with `short_text="skip"`.
+    fn sketch(&self, py: Python<'_>, data: String) -> PyResult<PyObject> {
+        let hasher = self.prototype.borrow(py);
+        let idle = self.idle.lock().unwrap().pop();
+        let mut minhash = idle.unwrap_or_else(|| hasher.new_sketcher());
+        let config = hasher.config();
+        let sketched = py.allow_threads(|| config.sketch_into(&mut minhash, data));
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.pool_size {
+            idle.push(minhash);
+        }
+        drop(idle);
+        let (signature, count) = sketched?;
+        if hasher.skips(count) {
+            return Ok(py.None());
+        }
+        signature.into_py(py)
+    }
+
+    #[getter]
+    fn sketch_size(&self) -> usize {
+        self.sketch_size
+    }
+
+    #[getter]
+    fn pool_size(&self) -> usize {
+        self.pool_size
+    }
+
+    fn fingerprint(&self, py: Python<'_>) -> u64 {
+        self.prototype.borrow(py).fingerprint()
+    }
+
+    /// Number of sketchers currently waiting to be reused.
+    fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+}
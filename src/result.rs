@@ -0,0 +1,191 @@
+use fnv::FnvHashMap;
+use pyo3::exceptions::PyKeyError;
+use pyo3::prelude::*;
+use pyo3::types::{PyIterator, PyList};
+
+/// Outcome of a `check_and_add` call: the matches, whether the item was
+/// inserted and, if so, where. Also reads as the `{id: similarity}` mapping
+/// of matches, which is what `check_and_add` returned before.
+#[pyclass(frozen)]
+pub struct CheckResult {
+    matches: FnvHashMap<String, f64>,
+    weights: FnvHashMap<String, f64>,
+    added: bool,
+    index: Option<usize>,
+    error: Option<String>,
+    truncated: bool,
+    skipped: bool,
+    overflow: bool,
+}
+
+impl CheckResult {
+    pub(crate) fn new(matches: FnvHashMap<String, f64>, index: Option<usize>) -> Self {
+        CheckResult {
+            matches,
+            weights: FnvHashMap::default(),
+            added: index.is_some(),
+            index,
+            error: None,
+            truncated: false,
+            skipped: false,
+            overflow: false,
+        }
+    }
+
+    /// Result of a text `short_text="skip"` left without shingles.
+    pub(crate) fn too_short() -> Self {
+        CheckResult {
+            skipped: true,
+            ..CheckResult::new(FnvHashMap::default(), None)
+        }
+    }
+
+    pub(crate) fn with_truncated(mut self, truncated: bool) -> Self {
+        self.truncated = truncated;
+        self
+    }
+
+    pub(crate) fn with_weights(mut self, weights: FnvHashMap<String, f64>) -> Self {
+        self.weights = weights;
+        self
+    }
+
+    pub(crate) fn with_overflow(mut self, overflow: bool) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Result of a batch item that raised `error` instead of being checked.
+    pub(crate) fn failed(error: String) -> Self {
+        CheckResult {
+            matches: FnvHashMap::default(),
+            weights: FnvHashMap::default(),
+            added: false,
+            index: None,
+            error: Some(error),
+            truncated: false,
+            skipped: false,
+            overflow: false,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.matches.is_empty()
+    }
+}
+
+#[pymethods]
+impl CheckResult {
+    #[getter]
+    fn matches(&self) -> FnvHashMap<String, f64> {
+        self.matches.clone()
+    }
+
+    /// `{id: weight}` of the matches, empty where weights are not tracked.
+    #[getter]
+    fn weights(&self) -> FnvHashMap<String, f64> {
+        self.weights.clone()
+    }
+
+    #[getter]
+    fn added(&self) -> bool {
+        self.added
+    }
+
+    /// Position the item was stored at, `None` when it was not added.
+    #[getter]
+    fn index(&self) -> Option<usize> {
+        self.index
+    }
+
+    /// Why a batch item could not be checked, `None` on success.
+    #[getter]
+    fn error(&self) -> Option<String> {
+        self.error.clone()
+    }
+
+    /// Whether a time budget ran out before every candidate was verified,
+    /// so `matches` may be incomplete.
+    #[getter]
+    fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Whether the text was too short to shingle under `short_text="skip"`,
+    /// so it was neither checked nor added.
+    #[getter]
+    fn skipped(&self) -> bool {
+        self.skipped
+    }
+
+    /// Whether more entries matched than `max_results`, so `matches` holds
+    /// only that many of them.
+    #[getter]
+    fn overflow(&self) -> bool {
+        self.overflow
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.matches.keys().cloned().collect()
+    }
+
+    fn values(&self) -> Vec<f64> {
+        self.matches.values().copied().collect()
+    }
+
+    fn items(&self) -> Vec<(String, f64)> {
+        self.matches.iter().map(|(k, &v)| (k.clone(), v)).collect()
+    }
+
+    #[pyo3(signature = (id, default = None))]
+    fn get(&self, id: &str, default: Option<f64>) -> Option<f64> {
+        self.matches.get(id).copied().or(default)
+    }
+
+    fn __len__(&self) -> usize {
+        self.matches.len()
+    }
+
+    fn __contains__(&self, id: &str) -> bool {
+        self.matches.contains_key(id)
+    }
+
+    fn __getitem__(&self, id: &str) -> PyResult<f64> {
+        self.matches
+            .get(id)
+            .copied()
+            .ok_or_else(|| PyKeyError::new_err(id.to_string()))
+    }
+
+    fn __iter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyIterator>> {
+        PyList::new(py, self.keys())?.as_any().try_iter()
+    }
+
+    fn __repr__(&self) -> String {
+        if let Some(error) = &self.error {
+            return format!("CheckResult(error={:?})", error);
+        }
+        if self.skipped {
+            return "CheckResult(skipped=True)".to_string();
+        }
+        let mut matches: Vec<_> = self.matches.iter().collect();
+        matches.sort_by(|a, b| a.0.cmp(b.0));
+        let matches = matches
+            .iter()
+            .map(|(k, v)| format!("'{}': {:?}", k, v))
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!(
+            "CheckResult(matches={{{}}}, added={}, index={}{}{})",
+            matches,
+            if self.added { "True" } else { "False" },
+            self.index.map_or("None".to_string(), |i| i.to_string()),
+            if self.truncated {
+                ", truncated=True"
+            } else {
+                ""
+            },
+            if self.overflow { ", overflow=True" } else { "" }
+        )
+    }
+}
@@ -0,0 +1,232 @@
+use crate::minhash::{SuperMinHasher, LSH};
+use fnv::FnvHashMap;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+
+/// Wilson score interval for a binomial proportion at 95% confidence.
+fn wilson_interval(successes: usize, n: usize) -> (f64, f64) {
+    let z = 1.959964;
+    let n = n as f64;
+    let p = successes as f64 / n;
+    let denom = 1.0 + z * z / n;
+    let center = (p + z * z / (2.0 * n)) / denom;
+    let half = z * (p * (1.0 - p) / n + z * z / (4.0 * n * n)).sqrt() / denom;
+    ((center - half).max(0.0), (center + half).min(1.0))
+}
+
+/// Draws a uniform sample of `sample_size` texts from `texts` in one pass
+/// and deduplicates the sample against a temporary index.
+///
+/// `rate` is the fraction of sampled documents that near-duplicate an
+/// earlier sampled one, with `lower`/`upper` its 95% Wilson interval; it is
+/// exact when the whole corpus fits in the sample and otherwise undercounts
+/// duplicates whose partners were not sampled. `duplicates_per_doc`
+/// extrapolates to the full corpus: it is an unbiased estimate of the mean
+/// number of other near-duplicates each document has. Every sampled text
+/// is sketched on its own, in parallel with the GIL released, leaving the
+/// hasher's state as it is. Texts `short_text="skip"` leaves out are
+/// neither sampled nor counted in `total`.
+pub(crate) fn estimate_dup_rate(
+    hasher: &SuperMinHasher,
+    texts: &Bound<'_, PyAny>,
+    sample_size: usize,
+    threshold: f64,
+    seed: Option<u64>,
+) -> PyResult<FnvHashMap<String, f64>> {
+    if sample_size < 2 {
+        return Err(PyValueError::new_err("sample_size must be at least 2"));
+    }
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_os_rng(),
+    };
+    let mut sample: Vec<String> = Vec::with_capacity(sample_size);
+    let mut total = 0usize;
+    for text in texts.try_iter()? {
+        let text: String = text?.extract()?;
+        if hasher.skips_text(&text)? {
+            continue;
+        }
+        if sample.len() < sample_size {
+            sample.push(text);
+        } else {
+            let j = rng.random_range(0..=total);
+            if j < sample_size {
+                sample[j] = text;
+            }
+        }
+        total += 1;
+    }
+    let n = sample.len();
+    if n < 2 {
+        return Err(PyValueError::new_err(
+            "need at least 2 documents to estimate a duplication rate",
+        ));
+    }
+    let signatures = texts.py().allow_threads(|| {
+        sample
+            .into_par_iter()
+            .map(|text| hasher.sketch_detached(text))
+            .collect::<PyResult<Vec<_>>>()
+    })?;
+    let mut lsh = LSH::new(None);
+    let (mut duplicates, mut pairs) = (0usize, 0usize);
+    for (i, (signature, _)) in signatures.into_iter().enumerate() {
+        let matches = lsh.similar(&signature, threshold)?;
+        if !matches.is_empty() {
+            duplicates += 1;
+            pairs += matches.len();
+        }
+        lsh.add_signature(&i.to_string(), signature)?;
+    }
+    let (lower, upper) = wilson_interval(duplicates, n);
+    let per_doc = 2.0 * pairs as f64 / n as f64 * (total - 1) as f64 / (n - 1) as f64;
+    let mut result = FnvHashMap::default();
+    result.insert("rate".to_string(), duplicates as f64 / n as f64);
+    result.insert("lower".to_string(), lower);
+    result.insert("upper".to_string(), upper);
+    result.insert("duplicates_per_doc".to_string(), per_doc);
+    result.insert("sampled".to_string(), n as f64);
+    result.insert("total".to_string(), total as f64);
+    Ok(result)
+}
+
+/// The closest entry of `lsh`, whose ids are positions, at least
+/// `threshold` similar to `signature`, the earliest on ties.
+fn closest(lsh: &LSH, signature: &[f32], threshold: f64) -> PyResult<Option<(usize, f64)>> {
+    Ok(lsh
+        .similar(signature, threshold)?
+        .into_iter()
+        .map(|(id, similarity)| (id.parse::<usize>().unwrap(), similarity))
+        .max_by(|x, y| x.1.total_cmp(&y.1).then(y.0.cmp(&x.0))))
+}
+
+/// Indexes every text of `a`, then checks each text of `b` against it.
+///
+/// `fraction` is the share of `b` near-duplicating some text of `a` at
+/// `threshold`, out of `total_b` texts. `examples` holds up to `examples`
+/// `(index in a, index in b, similarity)` pairs drawn uniformly from the
+/// overlapping texts of `b`, each with its closest match in `a`. Every text
+/// is sketched on its own, leaving the hasher's state as it is. Texts
+/// `short_text="skip"` leaves out are neither indexed nor checked and count
+/// in neither total; example indices still are input positions.
+pub(crate) fn corpus_overlap<'py>(
+    hasher: &SuperMinHasher,
+    a: &Bound<'py, PyAny>,
+    b: &Bound<'py, PyAny>,
+    threshold: f64,
+    examples: usize,
+    seed: Option<u64>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_os_rng(),
+    };
+    let mut lsh = LSH::new(None);
+    let mut total_a = 0usize;
+    for (i, text) in a.try_iter()?.enumerate() {
+        let (signature, count) = hasher.sketch_detached(text?.extract()?)?;
+        if !hasher.skips(count) {
+            lsh.add_signature(&i.to_string(), signature)?;
+            total_a += 1;
+        }
+    }
+    let mut sample: Vec<(usize, usize, f64)> = Vec::with_capacity(examples);
+    let (mut overlapping, mut total_b) = (0usize, 0usize);
+    for (position, text) in b.try_iter()?.enumerate() {
+        let (signature, count) = hasher.sketch_detached(text?.extract()?)?;
+        if hasher.skips(count) {
+            continue;
+        }
+        let best = closest(&lsh, &signature, threshold)?;
+        if let Some((i, similarity)) = best {
+            if sample.len() < examples {
+                sample.push((i, position, similarity));
+            } else {
+                let j = rng.random_range(0..=overlapping);
+                if j < examples {
+                    sample[j] = (i, position, similarity);
+                }
+            }
+            overlapping += 1;
+        }
+        total_b += 1;
+    }
+    let result = PyDict::new(a.py());
+    let fraction = if total_b > 0 {
+        overlapping as f64 / total_b as f64
+    } else {
+        0.0
+    };
+    result.set_item("fraction", fraction)?;
+    result.set_item("overlapping", overlapping)?;
+    result.set_item("total_a", total_a)?;
+    result.set_item("total_b", total_b)?;
+    sample.sort_by_key(|&(_, j, _)| j);
+    result.set_item("examples", sample)?;
+    Ok(result)
+}
+
+/// Indexes the `benchmark` texts, then reads `(id, text)` pairs from
+/// `corpus` `batch_size` at a time, sketching and checking each batch in
+/// parallel with the GIL released. Returns `(id, benchmark index,
+/// similarity)` for every corpus record at least `threshold` similar to a
+/// benchmark text, with its closest one, in corpus order. Texts
+/// `short_text="skip"` leaves out are neither indexed nor reported.
+pub(crate) fn find_contaminated(
+    py: Python<'_>,
+    hasher: &mut SuperMinHasher,
+    benchmark: Vec<String>,
+    corpus: &Bound<'_, PyAny>,
+    threshold: f64,
+    batch_size: usize,
+) -> PyResult<Vec<(String, usize, f64)>> {
+    if batch_size == 0 {
+        return Err(PyValueError::new_err("batch_size must be greater than 0"));
+    }
+    let mut lsh = LSH::new(None);
+    let signatures = py.allow_threads(|| hasher.sketch_parallel(benchmark))?;
+    for (i, (signature, count)) in signatures.into_iter().enumerate() {
+        if !hasher.skips(count) {
+            lsh.add_signature(&i.to_string(), signature)?;
+        }
+    }
+    let mut contaminated = Vec::new();
+    let mut records = corpus.try_iter()?;
+    loop {
+        let mut ids = Vec::with_capacity(batch_size);
+        let mut texts = Vec::with_capacity(batch_size);
+        for record in records.by_ref().take(batch_size) {
+            let (id, text): (String, String) = record?.extract()?;
+            ids.push(id);
+            texts.push(text);
+        }
+        if ids.is_empty() {
+            return Ok(contaminated);
+        }
+        let lsh = &lsh;
+        let best = py.allow_threads(|| {
+            let signatures = hasher.sketch_parallel(texts)?;
+            let hasher = &*hasher;
+            signatures
+                .par_iter()
+                .map(|(signature, count)| {
+                    if hasher.skips(*count) {
+                        Ok(None)
+                    } else {
+                        closest(lsh, signature, threshold)
+                    }
+                })
+                .collect::<PyResult<Vec<_>>>()
+        })?;
+        for (id, best) in ids.into_iter().zip(best) {
+            if let Some((i, similarity)) = best {
+                contaminated.push((id, i, similarity));
+            }
+        }
+    }
+}
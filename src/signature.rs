@@ -0,0 +1,158 @@
+use crate::minhash::SignatureDtype;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+use pyo3::IntoPyObjectExt;
+
+const MAGIC: &[u8; 4] = b"W6SG";
+const VERSION: u8 = 1;
+
+/// Codes of the dtype byte, in `SignatureDtype` names.
+const DTYPES: [(u8, &str); 4] = [(0, "f32"), (1, "f64"), (2, "u32"), (3, "u64")];
+
+fn dtype_code(dtype: SignatureDtype) -> u8 {
+    DTYPES.iter().find(|(_, n)| *n == dtype.name()).unwrap().0
+}
+
+fn width(dtype: SignatureDtype) -> usize {
+    match dtype {
+        SignatureDtype::F32 | SignatureDtype::U32 => 4,
+        SignatureDtype::F64 | SignatureDtype::U64 => 8,
+    }
+}
+
+/// Encodes one signature in a fixed layout meant to be read outside Python.
+/// All integers and floats are little-endian:
+///
+/// ```text
+/// "W6SG" version:u8 dtype:u8 has_fingerprint:u8 [fingerprint:u64] size:u32
+/// size * value crc:u32
+/// ```
+///
+/// where dtype is 0 for f32, 1 for f64, 2 for u32 and 3 for u64 values (see
+/// `SuperMinHasher.finalize(dtype=...)`), each 4 or 8 bytes wide, and the
+/// CRC-32 (IEEE) covers everything before it. `fingerprint` is that of the
+/// hasher that made the signature, if given.
+#[pyfunction]
+#[pyo3(signature = (values, dtype = "f32", fingerprint = None))]
+pub fn signature_to_bytes<'py>(
+    py: Python<'py>,
+    values: &Bound<'py, PyAny>,
+    dtype: &str,
+    fingerprint: Option<u64>,
+) -> PyResult<Bound<'py, PyBytes>> {
+    let dtype = SignatureDtype::parse(dtype)?;
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+    out.push(dtype_code(dtype));
+    match fingerprint {
+        Some(fingerprint) => {
+            out.push(1);
+            out.extend_from_slice(&fingerprint.to_le_bytes());
+        }
+        None => out.push(0),
+    }
+    let body: Vec<u8> = match dtype {
+        SignatureDtype::F32 => values
+            .extract::<Vec<f32>>()?
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect(),
+        SignatureDtype::F64 => values
+            .extract::<Vec<f64>>()?
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect(),
+        SignatureDtype::U32 => values
+            .extract::<Vec<u32>>()?
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect(),
+        SignatureDtype::U64 => values
+            .extract::<Vec<u64>>()?
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect(),
+    };
+    let size = body.len() / width(dtype);
+    out.extend_from_slice(&(size as u32).to_le_bytes());
+    out.extend_from_slice(&body);
+    let crc = crc32fast::hash(&out);
+    out.extend_from_slice(&crc.to_le_bytes());
+    Ok(PyBytes::new(py, &out))
+}
+
+/// Decodes `signature_to_bytes` output into a dict of `values`, `dtype` and
+/// `fingerprint` (`None` if it was not recorded).
+#[pyfunction]
+pub fn signature_from_bytes<'py>(py: Python<'py>, data: &[u8]) -> PyResult<Bound<'py, PyDict>> {
+    if data.len() < 7 || &data[..4] != MAGIC {
+        return Err(PyValueError::new_err("not a w6sketch signature"));
+    }
+    if data[4] != VERSION {
+        return Err(PyValueError::new_err(format!(
+            "unsupported signature version {}",
+            data[4]
+        )));
+    }
+    let name = DTYPES
+        .iter()
+        .find(|(code, _)| *code == data[5])
+        .map(|&(_, name)| name)
+        .ok_or_else(|| PyValueError::new_err(format!("unknown signature dtype {}", data[5])))?;
+    let dtype = SignatureDtype::parse(name)?;
+    let (fingerprint, header) = match data[6] {
+        0 => (None, 7),
+        _ if data.len() >= 15 => (
+            Some(u64::from_le_bytes(data[7..15].try_into().unwrap())),
+            15,
+        ),
+        _ => return Err(PyValueError::new_err("signature truncated in its header")),
+    };
+    if data.len() < header + 4 {
+        return Err(PyValueError::new_err("signature truncated in its header"));
+    }
+    let size = u32::from_le_bytes(data[header..header + 4].try_into().unwrap()) as usize;
+    let start = header + 4;
+    let body = size
+        .checked_mul(width(dtype))
+        .and_then(|len| len.checked_add(start))
+        .filter(|&end| end.checked_add(4) == Some(data.len()))
+        .ok_or_else(|| {
+            PyValueError::new_err(format!(
+                "signature has {} bytes, which does not fit {} {} slots",
+                data.len(),
+                size,
+                name
+            ))
+        })?;
+    let expected = u32::from_le_bytes(data[body..].try_into().unwrap());
+    if crc32fast::hash(&data[..body]) != expected {
+        return Err(PyValueError::new_err("signature checksum mismatch"));
+    }
+    let chunks = data[start..body].chunks_exact(width(dtype));
+    let values = match dtype {
+        SignatureDtype::F32 => chunks
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect::<Vec<_>>()
+            .into_py_any(py)?,
+        SignatureDtype::F64 => chunks
+            .map(|b| f64::from_le_bytes(b.try_into().unwrap()))
+            .collect::<Vec<_>>()
+            .into_py_any(py)?,
+        SignatureDtype::U32 => chunks
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .collect::<Vec<_>>()
+            .into_py_any(py)?,
+        SignatureDtype::U64 => chunks
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+            .collect::<Vec<_>>()
+            .into_py_any(py)?,
+    };
+    let dict = PyDict::new(py);
+    dict.set_item("values", values)?;
+    dict.set_item("dtype", name)?;
+    dict.set_item("fingerprint", fingerprint)?;
+    Ok(dict)
+}
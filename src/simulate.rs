@@ -0,0 +1,97 @@
+use crate::minhash::{Sketcher, LSH};
+use fnv::FnvHashMap;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Monte-Carlo estimate of how the built-in `LSH` behaves on pairs of
+/// documents of `set_size` shingles whose Jaccard similarity sweeps from 0
+/// to 1 in `steps` points. Each trial sketches a fresh pair into signatures
+/// of length `size`, indexes one of them in an `LSH` with the given `bits`
+/// and `key_bytes` and queries it with the other, so the curves follow the
+/// real bucketing (one bucket per slot, keyed on the `key_bytes` most
+/// significant bytes of its value) and verification. A pair is a candidate
+/// when the two share a bucket and is detected when verification then
+/// reaches `threshold`. `similarity` holds the exact Jaccard similarity of
+/// the generated pairs, which is the target rounded to whole shingles.
+#[pyfunction]
+#[pyo3(signature = (threshold, size, trials = 1000, steps = 101, seed = None, bits = None, key_bytes = 4, set_size = 100))]
+#[allow(clippy::too_many_arguments)]
+pub fn simulate(
+    py: Python<'_>,
+    threshold: f64,
+    size: usize,
+    trials: usize,
+    steps: usize,
+    seed: Option<u64>,
+    bits: Option<u32>,
+    key_bytes: usize,
+    set_size: usize,
+) -> PyResult<FnvHashMap<String, Vec<f64>>> {
+    if size == 0 || set_size == 0 {
+        return Err(PyValueError::new_err(
+            "size and set_size must be greater than 0",
+        ));
+    }
+    if trials == 0 || steps < 2 {
+        return Err(PyValueError::new_err(
+            "trials must be greater than 0 and steps at least 2",
+        ));
+    }
+    // Fail on bad options before spending any trials.
+    LSH::new(None).with_bits(bits)?.with_key_bytes(key_bytes)?;
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_os_rng(),
+    };
+    py.allow_threads(|| {
+        let mut similarity = Vec::with_capacity(steps);
+        let mut candidate = Vec::with_capacity(steps);
+        let mut detected = Vec::with_capacity(steps);
+        let mut bucket_collisions = Vec::with_capacity(steps);
+        let mut minhash = Sketcher::f32(size);
+        for step in 0..steps {
+            let target = step as f64 / (steps - 1) as f64;
+            // Two sets of `set_size` sharing `shared` shingles have Jaccard
+            // similarity shared / (2 * set_size - shared).
+            let shared = (2.0 * set_size as f64 * target / (1.0 + target)).round() as usize;
+            let (mut n_candidate, mut n_detected, mut n_buckets) = (0usize, 0usize, 0usize);
+            for _ in 0..trials {
+                let common: Vec<u64> = (0..shared).map(|_| rng.random()).collect();
+                let mut sketch = |rng: &mut StdRng| {
+                    minhash.reinit();
+                    for &h in &common {
+                        minhash.sketch(h);
+                    }
+                    for _ in shared..set_size {
+                        minhash.sketch(rng.random());
+                    }
+                    minhash.signature().into_f32()
+                };
+                let indexed = sketch(&mut rng)?;
+                let query = sketch(&mut rng)?;
+                let mut lsh = LSH::new(None).with_bits(bits)?.with_key_bytes(key_bytes)?;
+                lsh.add_signature("0", indexed)?;
+                let colliding = lsh.bucket_collisions(&query);
+                n_buckets += colliding;
+                if colliding > 0 {
+                    n_candidate += 1;
+                    if !lsh.similar(&query, threshold)?.is_empty() {
+                        n_detected += 1;
+                    }
+                }
+            }
+            similarity.push(shared as f64 / (2 * set_size - shared) as f64);
+            candidate.push(n_candidate as f64 / trials as f64);
+            detected.push(n_detected as f64 / trials as f64);
+            bucket_collisions.push(n_buckets as f64 / trials as f64);
+        }
+        let mut result = FnvHashMap::default();
+        result.insert("similarity".to_string(), similarity);
+        result.insert("candidate_probability".to_string(), candidate);
+        result.insert("detection_probability".to_string(), detected);
+        result.insert("mean_bucket_collisions".to_string(), bucket_collisions);
+        Ok(result)
+    })
+}
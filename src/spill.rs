@@ -0,0 +1,79 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Distinguishes the spill files of one process.
+static NEXT_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// Where a spilled signature sits in its `SpillFile`.
+#[derive(Clone, Copy)]
+pub(crate) struct SpillSlot {
+    offset: u64,
+    len: u32,
+}
+
+/// Append-only temp file holding the signatures an index moved out of
+/// memory, see `LSH.with_max_memory_bytes`. The file is unlinked as soon as
+/// it is opened, so it goes away with the last index or reader holding it.
+/// Each signature is stored as its slots, each a length byte and the slot
+/// bytes.
+pub(crate) struct SpillFile {
+    file: Mutex<File>,
+}
+
+impl SpillFile {
+    pub(crate) fn create() -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "w6sketch-spill-{}-{}",
+            std::process::id(),
+            NEXT_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // Best effort: platforms that cannot unlink an open file keep it.
+        let _ = fs::remove_file(&path);
+        Ok(SpillFile {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends `data_bytes` and returns where it was written.
+    pub(crate) fn write(&self, data_bytes: &[Vec<u8>]) -> io::Result<SpillSlot> {
+        let mut encoded = Vec::with_capacity(data_bytes.iter().map(|y| y.len() + 1).sum());
+        for y in data_bytes {
+            encoded.push(y.len() as u8);
+            encoded.extend_from_slice(y);
+        }
+        let mut file = self.file.lock().unwrap();
+        let offset = file.seek(SeekFrom::End(0))?;
+        file.write_all(&encoded)?;
+        Ok(SpillSlot {
+            offset,
+            len: encoded.len() as u32,
+        })
+    }
+
+    /// The signature written at `slot`.
+    pub(crate) fn read(&self, slot: SpillSlot) -> io::Result<Vec<Vec<u8>>> {
+        let mut encoded = vec![0u8; slot.len as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(slot.offset))?;
+            file.read_exact(&mut encoded)?;
+        }
+        let mut data_bytes = Vec::new();
+        let mut rest = &encoded[..];
+        while let Some((&len, tail)) = rest.split_first() {
+            let (y, tail) = tail.split_at_checked(len as usize).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "truncated spill record")
+            })?;
+            data_bytes.push(y.to_vec());
+            rest = tail;
+        }
+        Ok(data_bytes)
+    }
+}
@@ -0,0 +1,156 @@
+use crate::minhash::SuperMinHasher;
+use crate::result::CheckResult;
+use fnv::{FnvHashMap, FnvHashSet};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::collections::VecDeque;
+
+struct Entry {
+    seq: u64,
+    id: String,
+    signature: Vec<f32>,
+}
+
+/// Near-duplicate suppression over a sliding window: only the last
+/// `capacity` added items are remembered, older ones are evicted together
+/// with their bucket entries.
+#[pyclass]
+pub struct StreamingDeduper {
+    hasher: Py<SuperMinHasher>,
+    capacity: usize,
+    buckets: FnvHashMap<u32, FnvHashSet<u64>>,
+    window: VecDeque<Entry>,
+    next_seq: u64,
+}
+
+impl StreamingDeduper {
+    fn get(&self, seq: u64) -> Option<&Entry> {
+        let front = self.window.front()?.seq;
+        self.window.get(seq.checked_sub(front)? as usize)
+    }
+
+    fn evict(&mut self) {
+        let Some(entry) = self.window.pop_front() else {
+            return;
+        };
+        for value in &entry.signature {
+            let key = value.to_bits();
+            if let Some(bucket) = self.buckets.get_mut(&key) {
+                bucket.remove(&entry.seq);
+                if bucket.is_empty() {
+                    self.buckets.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+#[pymethods]
+impl StreamingDeduper {
+    /// Keyword arguments other than `capacity` and `size` are passed to
+    /// `SuperMinHasher`.
+    #[new]
+    #[pyo3(signature = (capacity, size, **kwargs))]
+    fn new(
+        py: Python<'_>,
+        capacity: usize,
+        size: usize,
+        kwargs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<Self> {
+        if capacity == 0 {
+            return Err(PyValueError::new_err("capacity must be greater than 0"));
+        }
+        let hasher: Py<SuperMinHasher> = py
+            .get_type::<SuperMinHasher>()
+            .call((size,), kwargs)?
+            .extract()?;
+        Ok(StreamingDeduper {
+            hasher,
+            capacity,
+            buckets: FnvHashMap::default(),
+            window: VecDeque::with_capacity(capacity),
+            next_seq: 0,
+        })
+    }
+
+    /// Returns the items in the window at least `threshold` similar to
+    /// `data`, then adds `data` if `add` is set and nothing matched (or
+    /// `add_if_dup` is set), evicting the oldest item when the window is full.
+    /// The result's `index` is where the item went in the window, counted
+    /// from the oldest item still held.
+    #[pyo3(signature = (new_id, data, threshold = 0.5, add = true, add_if_dup = false))]
+    fn check_and_add(
+        &mut self,
+        py: Python<'_>,
+        new_id: &str,
+        data: String,
+        threshold: f64,
+        add: bool,
+        add_if_dup: bool,
+    ) -> PyResult<CheckResult> {
+        let (signature, count) = self.hasher.borrow_mut(py).sketch_counted(data)?;
+        if self.hasher.borrow(py).skips(count) {
+            return Ok(CheckResult::too_short());
+        }
+        let candidates: FnvHashSet<u64> = signature
+            .iter()
+            .filter_map(|v| self.buckets.get(&v.to_bits()))
+            .flatten()
+            .copied()
+            .collect();
+        let mut result = FnvHashMap::default();
+        for seq in candidates {
+            if let Some(entry) = self.get(seq) {
+                let same = signature
+                    .iter()
+                    .zip(&entry.signature)
+                    .filter(|(a, b)| a == b)
+                    .count();
+                let similarity = same as f64 / signature.len() as f64;
+                if similarity >= threshold {
+                    result.insert(entry.id.clone(), similarity);
+                }
+            }
+        }
+        let mut index = None;
+        if add && (result.is_empty() || add_if_dup) {
+            if self.window.len() == self.capacity {
+                self.evict();
+            }
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            for value in &signature {
+                self.buckets.entry(value.to_bits()).or_default().insert(seq);
+            }
+            self.window.push_back(Entry {
+                seq,
+                id: new_id.to_string(),
+                signature,
+            });
+            index = Some(self.window.len() - 1);
+        }
+        Ok(CheckResult::new(result, index))
+    }
+
+    /// Whether anything in the window is at least `threshold` similar to
+    /// `data`. Does not add `data`.
+    #[pyo3(signature = (data, threshold = 0.5))]
+    fn seen(&mut self, py: Python<'_>, data: String, threshold: f64) -> PyResult<bool> {
+        Ok(!self
+            .check_and_add(py, "", data, threshold, false, false)?
+            .is_empty())
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.window.iter().map(|e| e.id.clone()).collect()
+    }
+
+    fn length(&self) -> usize {
+        self.window.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
@@ -0,0 +1,86 @@
+use crate::hll::mix;
+use fnv::{FnvHashMap, FnvHasher};
+use probminhash::nohasher::NoHashHasher;
+use probminhash::probminhasher::probminhash3::ProbMinHash3a;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use std::hash::Hasher;
+
+/// ProbMinHash sketches of weighted token sets, such as term frequencies or
+/// TF-IDF scores. Each slot holds the hash of the token that won it, tokens
+/// winning in proportion to their weight, so the fraction of equal slots of
+/// two signatures (see `jaccard`) estimates their probability Jaccard
+/// similarity: the weighted Jaccard similarity made insensitive to scaling
+/// all weights of one document.
+#[pyclass]
+pub struct WeightedMinHasher {
+    size: usize,
+    /// Mixed into every token hash, so hashers with different seeds draw
+    /// independent signatures.
+    seed: Option<u64>,
+}
+
+impl WeightedMinHasher {
+    fn token_hash(&self, token: &str) -> u64 {
+        let mut h = FnvHasher::default();
+        h.write(token.as_bytes());
+        match self.seed {
+            Some(seed) => mix(h.finish() ^ seed),
+            None => h.finish(),
+        }
+    }
+}
+
+#[pymethods]
+impl WeightedMinHasher {
+    #[new]
+    #[pyo3(signature = (size, seed = None))]
+    fn new(size: usize, seed: Option<u64>) -> PyResult<Self> {
+        if size < 2 {
+            return Err(PyValueError::new_err("size must be at least 2"));
+        }
+        Ok(WeightedMinHasher { size, seed })
+    }
+
+    #[getter]
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Signature of `tokens` weighted by `weights`, or by 1 each when not
+    /// given. The weights of a repeated token add up, so a token list alone
+    /// gives a term-frequency weighted sketch. Tokens of weight 0 are left
+    /// out.
+    #[pyo3(signature = (tokens, weights = None))]
+    fn sketch(&self, tokens: Vec<String>, weights: Option<Vec<f64>>) -> PyResult<Vec<u64>> {
+        if let Some(weights) = &weights {
+            if weights.len() != tokens.len() {
+                return Err(PyValueError::new_err(format!(
+                    "got {} weights for {} tokens",
+                    weights.len(),
+                    tokens.len()
+                )));
+            }
+            if weights.iter().any(|w| !w.is_finite() || *w < 0.0) {
+                return Err(PyValueError::new_err(
+                    "weights must be finite and non-negative",
+                ));
+            }
+        }
+        let mut set: FnvHashMap<u64, f64> = FnvHashMap::default();
+        for (i, token) in tokens.iter().enumerate() {
+            let weight = weights.as_ref().map_or(1.0, |weights| weights[i]);
+            if weight > 0.0 {
+                *set.entry(self.token_hash(token)).or_default() += weight;
+            }
+        }
+        if set.is_empty() {
+            return Err(PyValueError::new_err(
+                "nothing to sketch: no token has a positive weight",
+            ));
+        }
+        let mut sketcher = ProbMinHash3a::<u64, NoHashHasher>::new(self.size, 0);
+        sketcher.hash_weigthed_hashmap(&set);
+        Ok(sketcher.get_signature().clone())
+    }
+}